@@ -0,0 +1,144 @@
+#![feature(test)]
+#![feature(io)]
+#![feature(collections)]
+
+//! Parse and lookup throughput on multi-megabyte configurations, for
+//! `ConfigParser` and the borrowed `ConfigRef`. The `_regex` benches
+//! are the baseline: the regular expressions which the line grammar
+//! and interpolation used before the hand-written lexer, on the same
+//! text. Run with `cargo bench`.
+
+extern crate test;
+extern crate regex;
+extern crate conparse;
+
+use std::old_io::BufReader;
+use test::{Bencher,black_box};
+use regex::{Regex,Captures};
+use conparse::conparse::{ConfigParser,ConfigRef,Events,ParserOptions};
+
+const SECTIONS : usize = 2000;
+const OPTIONS : usize = 40;
+
+// about 3MB of text: sections of plain, interpolated and continued
+// values, with comments between them
+fn big_config() -> String {
+    let mut s = String::new();
+    s.push_str("[DEFAULT]\nroot = /srv/data\n\n");
+    for i in 0..SECTIONS {
+        s.push_str(format!("# section number {}\n[section{}]\n", i, i).as_slice());
+        for j in 0..OPTIONS {
+            match j % 4 {
+                0 => s.push_str(format!("path{} = %(root)s/s{}/o{}\n", j, i, j).as_slice()),
+                1 => s.push_str(format!("list{} = alpha, beta, \\\n    gamma{}\n",
+                                        j, j).as_slice()),
+                _ => s.push_str(format!("option{} = value number {} of {}\n", j, j, i).as_slice())
+            }
+        }
+        s.push_str("\n");
+    }
+    s
+}
+
+// the options looked up by the get benchmarks
+fn lookups() -> Vec<(String, String)> {
+    (0..1000).map(|n| (format!("section{}", (n * 7) % SECTIONS),
+                       match n % 3 {
+                           0 => format!("path{}", (n % 10) * 4),
+                           _ => format!("option{}", (n % 10) * 4 + 2)
+                       })).collect()
+}
+
+#[bench]
+fn parse(b : &mut Bencher) {
+    let text = big_config();
+    b.bytes = text.len() as u64;
+    b.iter(|| black_box(ConfigParser::from_str(text.as_slice(), &[])));
+}
+
+#[bench]
+fn parse_borrowed(b : &mut Bencher) {
+    let text = big_config();
+    b.bytes = text.len() as u64;
+    b.iter(|| black_box(ConfigRef::from_str(text.as_slice(), &[])));
+}
+
+#[bench]
+fn lex(b : &mut Bencher) {
+    let text = big_config();
+    let opts = ParserOptions::new();
+    b.bytes = text.len() as u64;
+    b.iter(|| {
+        let mut r = BufReader::new(text.as_bytes());
+        black_box(Events::new(&mut r, &opts).count())
+    });
+}
+
+// the grammar the lexer replaced, applied to each line with text as
+// the old reader did: section headers, then options
+#[bench]
+fn lex_regex(b : &mut Bencher) {
+    let text = big_config();
+    let sect_re = Regex::new(r"^\[\s*([^\]\s](?:[^\]]*[^\]\s])?)\s*\](\s*[#;].*)?$").unwrap();
+    let option_re = Regex::new(r"^([^:=\s\[](?:[^:=]*[^:=\s])?)(\s*[:=]\s*(.*))?$").unwrap();
+    b.bytes = text.len() as u64;
+    b.iter(|| {
+        let mut n = 0;
+        for l in text.as_slice().lines() {
+            let tl = l.trim();
+            if tl.is_empty() || tl.starts_with("#") || tl.starts_with(";") {
+                continue;
+            }
+            match sect_re.captures(tl) {
+                Some(c) => { black_box(c.at(1)); },
+                None => if let Some(c) = option_re.captures(tl) {
+                    black_box(c.at(3));
+                }
+            }
+            n += 1;
+        }
+        black_box(n)
+    });
+}
+
+#[bench]
+fn get(b : &mut Bencher) {
+    let text = big_config();
+    let cp = ConfigParser::from_str(&text[..], &[]);
+    let keys = lookups();
+    b.iter(|| {
+        for &(ref s, ref o) in keys.iter() {
+            black_box(cp.get(s.as_slice(), o.as_slice()).unwrap());
+        }
+    });
+}
+
+// interpolation as it was before the lexer: the raw value, with each
+// `%(name)s` found by a regular expression
+#[bench]
+fn get_regex(b : &mut Bencher) {
+    let text = big_config();
+    let cp = ConfigParser::from_str(text.as_slice(), &[]);
+    let interp_re = Regex::new(r"(%\(\s*([^)\s](?:[^)]*[^)\s])?)\s*\)s)").unwrap();
+    let keys = lookups();
+    b.iter(|| {
+        for &(ref s, ref o) in keys.iter() {
+            let raw = cp.get_raw(s.as_slice(), o.as_slice()).unwrap();
+            black_box(interp_re.replace_all(raw.as_slice(), |c : &Captures| {
+                cp.get_raw(s.as_slice(), c.at(2).unwrap()).unwrap()
+            }));
+        }
+    });
+}
+
+#[bench]
+fn get_borrowed(b : &mut Bencher) {
+    let text = big_config();
+    let cr = ConfigRef::from_str(&text[..], &[]);
+    let keys = lookups();
+    b.iter(|| {
+        for &(ref s, ref o) in keys.iter() {
+            black_box(cr.get(s.as_slice(), o.as_slice()).unwrap());
+        }
+    });
+}
@@ -1,11 +1,7 @@
-extern crate regex;
-extern crate core;
-
-use self::regex::{Regex,Captures};
-use self::core::num::{ParseIntError,ParseFloatError};
-
 use std::collections::{HashMap,HashSet};
-use std::collections::hash_map::{Keys,Iter,Entry};
+use std::collections::hash_map::Entry;
+use std::slice;
+use std::borrow::Cow;
 use std::error::Error;
 use std::fmt::{Display,Formatter,Debug};
 use std::fmt;
@@ -14,16 +10,47 @@ use std::old_io::{Open,IoError,ReadWrite,MemWriter,MemReader,
                   BufferedReader,IoResult,IoErrorKind,File,standard_error};
 use std::ascii::OwnedAsciiExt;
 use std::str::FromStr;
+use std::char;
+use std::old_io::fs::{readdir,PathExtensions};
 use expand::expand_homedir;
+use ordered::{OrderedMap,Iter};
 use std::env;
 
 
+/// Where the value of an option came from
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Origin {
+    /// Read from the named source (a file name, `<string>` or
+    /// `<reader N>` for the Nth reader), at the given line
+    Source(String, usize),
+    /// Set with `ConfigParser::set`
+    Programmatic,
+    /// A default given to the constructor
+    Default
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Origin::Source(ref s, line) => write!(f, "{} line {}", s, line),
+            Origin::Programmatic => write!(f, "set programmatically"),
+            Origin::Default => write!(f, "constructor default")
+        }
+    }
+}
+
 pub struct InterpString {
-    raw_string : String
+    // the values, oldest first: just the one unless the option is
+    // multi-valued. get_raw() and friends use the last one
+    values : Vec<String>,
+    // trailing inline comment (prefix included), if one was stripped
+    comment : Option<String>,
+    // where the (last) value was set
+    origin : Origin
     // maybe some fields for caching interpolated values?
 }
 
-pub type Props = HashMap<String, InterpString>;
+pub type Props = OrderedMap<InterpString>;
 
 /// A structure for storing INI style key,value pairs
 /// within a set of named sections
@@ -34,10 +61,238 @@ pub struct ConfigParser {
     /// internal HashMap is a mapping from a String (key name) to
     /// another String (the value of the option)
     
-    sections: HashMap<String, Props>,
-    s_re : Regex, // [ section ] regex
-    o_re : Regex, // option key : value regex
-    i_re : Regex // %(option)s interpolation regex
+    sections: OrderedMap<Props>,
+    /// default_sect - the options of the default section (normally
+    /// `[DEFAULT]`), which every other section falls back to
+    default_sect: Props,
+    /// parents - the declared parent of each section which inherits
+    /// options from another
+    parents: HashMap<String, String>,
+    /// opts - settings which govern reading and writing
+    opts: ParserOptions,
+    /// parse_errors - problems found in the sources read so far
+    parse_errors: Vec<ParseError>,
+    /// doc - in lossless mode, the text read (and edited since), to be
+    /// written back as it is
+    doc: Vec<DocLine>
+}
+
+/// Settings which change the way a `ConfigParser` reads and writes
+/// configuration text. `ParserOptions::new()` yields the defaults,
+/// which are the same as for a parser built with `ConfigParser::new`.
+#[derive(Clone,Debug)]
+pub struct ParserOptions {
+    /// Prefixes (such as `;` or `#`) which start a comment after an
+    /// option value, like Python's `inline_comment_prefixes`. A
+    /// prefix only counts if it begins the value or follows
+    /// whitespace, and is not inside double quotes or escaped with a
+    /// `\`. The comment is kept with the option so that it can be
+    /// written out again. Empty by default, in which case values are
+    /// taken verbatim.
+    pub inline_comment_prefixes : Vec<String>,
+    /// Normally a comment line met in the middle of a backslash
+    /// continuation ends the continued line. If this is `true`, such
+    /// comment lines are skipped instead, and the continuation
+    /// carries on with the next non-comment line.
+    pub comments_in_continuations : bool,
+    /// Python style continuations: a line indented deeper than the
+    /// option line above it continues that option's value, with the
+    /// lines joined by newlines (blank lines inside the value are
+    /// kept). Backslash continuations still work as usual.
+    pub indent_continuation : bool,
+    /// Values written in double quotes have the quotes removed and
+    /// backslash escapes (`\\`, `\"`, `\'`, `\n`, `\r`, `\t`,
+    /// `\0` and `\uXXXX`) decoded, so that leading and trailing
+    /// whitespace, comment characters and the like can be kept. When
+    /// writing, values which would not otherwise read back unchanged
+    /// are quoted.
+    pub quoted_values : bool,
+    /// Like Python's `strict` flag: a section or option which appears
+    /// twice within a single source is an error (`DuplicateSection`
+    /// or `DuplicateOption`), rather than being quietly merged or
+    /// overwritten. Later sources may still override earlier ones.
+    /// Repeated options are not duplicates when `multi_value` is set,
+    /// since that is how further values are given, and neither are
+    /// `+=` lines.
+    pub strict : bool,
+    /// What to do with options which come before any section header
+    pub global_options : GlobalOptions,
+    /// The name of the section which, as in Python, holds defaults
+    /// for every other section: its options are used by any section
+    /// which lacks them, can be interpolated into any section, and
+    /// are written out first. It is not listed by `sections()`.
+    /// `Some("DEFAULT")` by default; `None` makes it an ordinary
+    /// section name.
+    pub default_section : Option<String>,
+    /// Section headers of the form `[child : parent]` declare that
+    /// `child` inherits options it does not set itself from `parent`
+    pub explicit_inheritance : bool,
+    /// A section with a dotted name, like `[db.replica]`, inherits
+    /// options it does not set itself from `[db]`
+    pub dotted_inheritance : bool,
+    /// Repeating an option within a section adds another value to
+    /// it, rather than replacing it, and PHP style `key[] = value`
+    /// lines do the same. `get` returns the last value, and
+    /// `get_all` all of them.
+    pub multi_value : bool,
+    /// `key += value` adds to the value the option already has,
+    /// or would inherit or default to: as another value in
+    /// `multi_value` mode, otherwise separated by a space. With
+    /// `multi_value` set, an empty `key =` clears the option's
+    /// values. Later sources can so extend or reset lists set by
+    /// earlier ones, in the manner of systemd unit files.
+    pub append_operator : bool,
+    /// Lines of the form `include = path` or `!include path` read
+    /// the named file in place, as if its contents appeared
+    /// there (though it starts outside any section). Relative
+    /// paths are taken relative to the directory of the including
+    /// file. The last part of the path may contain `*` and `?`
+    /// wildcards, and `include_dir = dir` or `!include_dir dir`
+    /// reads a directory as `read_dir` does
+    pub allow_includes : bool,
+    /// How deeply included files may themselves include others
+    pub max_include_depth : usize,
+    /// Write sections and options in sorted order, rather than the
+    /// order they were read or added in
+    pub sorted_output : bool,
+    /// Keep the text read, comments, blank lines and all, so that
+    /// writing reproduces it exactly, though always in UTF-8 (see
+    /// `encoding`). `set`, `remove_option`,
+    /// `add_section` and `remove_section` then change only the lines
+    /// they affect, and new options go at the end of their section.
+    /// The text of included files is not kept, and other options
+    /// (such as `sorted_output`) have no effect on writing.
+    pub lossless : bool,
+    /// Bounds on how much text is read, and how far values are
+    /// expanded, for configuration from untrusted sources. None are
+    /// set by default.
+    pub limits : Limits,
+    /// How the bytes of files and readers are decoded into text.
+    /// Whatever the encoding, a UTF-8 byte order mark at the start of
+    /// the text is dropped, and Windows `\r\n` line endings are read
+    /// as `\n` (though both are kept in `lossless` mode). Text is
+    /// always written as UTF-8.
+    pub encoding : Encoding
+}
+
+/// The character encodings configuration files may be read in
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Encoding {
+    /// UTF-8, where invalid bytes are a read error (the default)
+    Utf8Strict,
+    /// UTF-8, where invalid bytes are replaced by U+FFFD
+    Utf8Lossy,
+    /// ISO 8859-1, where each byte is the character of the same
+    /// code point
+    Latin1,
+    /// Windows code page 1252, the usual encoding of text saved by
+    /// older Windows tools: Latin-1, but with printable characters
+    /// (such as the euro sign, curly quotes and dashes) for most of
+    /// the bytes 0x80 to 0x9F
+    Windows1252
+}
+
+// the characters of Windows-1252 bytes 0x80 to 0x9F, which differ
+// from Latin-1. The five bytes it leaves undefined keep their Latin-1
+// control characters, as web browsers do
+static WINDOWS_1252 : [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}'];
+
+/// Limits on the size and complexity of configuration text, and of
+/// the values expanded from it, so that a hostile file cannot make
+/// a parser use unbounded memory or time. Each limit is `None` for
+/// no limit; going over one is a `LimitExceeded` error.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Limits {
+    /// The longest physical line, in bytes, which will be read
+    pub max_line_length : Option<usize>,
+    /// The longest logical line, in bytes, once continuation lines
+    /// are joined onto it
+    pub max_logical_line_length : Option<usize>,
+    /// How many sections may be read (the default section aside)
+    pub max_sections : Option<usize>,
+    /// How many options may be read, counting those in every
+    /// section and the defaults
+    pub max_options : Option<usize>,
+    /// How deeply interpolations may nest: `%(a)s` where `a` holds
+    /// `%(b)s` is two deep
+    pub max_interpolation_depth : Option<usize>,
+    /// The longest value, in bytes, which appending (`key += ...`)
+    /// or interpolation may build
+    pub max_value_length : Option<usize>,
+    /// How many values one multi-valued option may hold
+    pub max_values : Option<usize>
+}
+
+impl Limits {
+    /// No limits at all
+    pub fn new() -> Limits {
+        Limits { max_line_length : None,
+                 max_logical_line_length : None,
+                 max_sections : None,
+                 max_options : None,
+                 max_interpolation_depth : None,
+                 max_value_length : None,
+                 max_values : None }
+    }
+
+    /// Limits which allow any reasonable hand written configuration,
+    /// for reading files from untrusted sources
+    pub fn untrusted() -> Limits {
+        Limits { max_line_length : Some(4096),
+                 max_logical_line_length : Some(65536),
+                 max_sections : Some(1000),
+                 max_options : Some(10000),
+                 max_interpolation_depth : Some(10),
+                 max_value_length : Some(65536),
+                 max_values : Some(1000) }
+    }
+}
+
+/// The treatment of options which appear before the first section
+/// header of a source, as found in git-style or `php.ini` files
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum GlobalOptions {
+    /// Warn about, and ignore, such options (the default)
+    Discard,
+    /// Collect them into a section of the given name
+    Section(String),
+    /// Add them to the default section, as if they came under its
+    /// header
+    Defaults
+}
+
+impl ParserOptions {
+    pub fn new() -> ParserOptions {
+        ParserOptions { inline_comment_prefixes : vec![],
+                        comments_in_continuations : false,
+                        indent_continuation : false,
+                        quoted_values : false,
+                        strict : false,
+                        global_options : GlobalOptions::Discard,
+                        default_section : Some("DEFAULT".to_string()),
+                        explicit_inheritance : false,
+                        dotted_inheritance : false,
+                        multi_value : false,
+                        append_operator : false,
+                        allow_includes : false,
+                        max_include_depth : 10,
+                        sorted_output : false,
+                        lossless : false,
+                        limits : Limits::new(),
+                        encoding : Encoding::Utf8Strict }
+    }
+
+    // true if s names the default section
+    fn is_default_section(&self, s : &str) -> bool {
+        match self.default_section {
+            Some(ref d) => d.as_slice() == s,
+            None => false
+        }
+    }
 }
 
 #[derive(Debug,Copy,PartialEq,Eq,Clone)]
@@ -48,12 +303,24 @@ pub enum FetchErrorKind {
     NoSuchOption,
     /// An attempt was made to add a section which already exists
     DuplicateSection,
+    /// An option was given twice in the same section of one source
+    DuplicateOption,
     /// An interpolation refers to an option which does not exist
     InterpolationError,
     /// An interpolation chain is circular
     InterpolationCircularity,
+    /// A section inherits (indirectly) from itself
+    InheritanceCircularity,
+    /// A file includes (indirectly) itself
+    IncludeCircularity,
+    /// Included files are nested more deeply than allowed
+    IncludeDepthExceeded,
+    /// A required configuration file cannot be opened
+    UnreadableFile,
     /// An attempt was made to translate an invalid string to another type
-    InvalidLiteral
+    InvalidLiteral,
+    /// Text or a value went over one of the parser's `Limits`
+    LimitExceeded
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -88,12 +355,24 @@ fn fe_error(k : FetchErrorKind) -> FetchError {
         FetchErrorKind::NoSuchSection => FetchError::new(k, "No such configuration section", None),
         FetchErrorKind::NoSuchOption => FetchError::new(k, "No such configuration option", None),
         FetchErrorKind::DuplicateSection => FetchError::new(k, "Section already exists", None),
+        FetchErrorKind::DuplicateOption => FetchError::new(k, "Option already exists", None),
         FetchErrorKind::InterpolationError => FetchError::new(k, "Interpolation into option failed", None),
         FetchErrorKind::InterpolationCircularity => FetchError::new(k, "Interpolation is infinitely recursive", None),
+        FetchErrorKind::InheritanceCircularity => FetchError::new(k, "Section inheritance is circular", None),
+        FetchErrorKind::IncludeCircularity => FetchError::new(k, "File inclusion is circular", None),
+        FetchErrorKind::IncludeDepthExceeded => FetchError::new(k, "Included files are nested too deeply", None),
+        FetchErrorKind::UnreadableFile => FetchError::new(k, "Configuration file cannot be opened", None),
         FetchErrorKind::InvalidLiteral => FetchError::new(k, "Value cannot be parsed into desired type", None),
+        FetchErrorKind::LimitExceeded => FetchError::new(k, "Configuration exceeds a size or complexity limit", None),
     }
 }
 
+fn fe_error_detail(k : FetchErrorKind, detail : String) -> FetchError {
+    let mut fe = fe_error(k);
+    fe.detail = Some(detail);
+    fe
+}
+
 impl Display for FetchError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
@@ -105,6 +384,108 @@ impl Display for FetchError {
     }
 }
 
+/// The kinds of problem found when reading a configuration source
+#[derive(Debug,Copy,PartialEq,Eq,Clone)]
+pub enum ParseErrorKind {
+    /// A line is neither a section header nor an option
+    InvalidLine,
+    /// The source could not be read (any further)
+    ReadError,
+    /// Reading stopped with a `FetchError` of the given kind, such as
+    /// a duplicate in `strict` mode or a circular include
+    Config(FetchErrorKind)
+}
+
+/// A problem found reading a configuration source, and where it was
+/// found. Lines and columns count from 1; the column is 0 where it
+/// has no meaning, as for read errors.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseError {
+    kind: ParseErrorKind,
+    description: &'static str,
+    detail: Option<String>,
+    source: String,
+    line: usize,
+    column: usize,
+    text: String
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        self.description
+    }
+}
+
+impl ParseError {
+    pub fn kind(&self) -> ParseErrorKind {
+        self.kind
+    }
+
+    pub fn detail(&self) -> Option<String> {
+        self.detail.clone()
+    }
+
+    /// The file name of the source, or `<string>` or `<reader N>`
+    pub fn source(&self) -> String {
+        self.source.clone()
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// The offending text, without surrounding whitespace
+    pub fn text(&self) -> String {
+        self.text.clone()
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(write!(f, "{}:{}:{}: {}", self.source, self.line, self.column, self.description));
+        if let Some(ref d) = self.detail {
+            try!(write!(f, " ({})", d));
+        }
+        if self.text.is_empty() {
+            Ok(())
+        } else {
+            write!(f, ": {}", self.text)
+        }
+    }
+}
+
+// record a problem found reading a source
+fn parse_error(cp : &mut ConfigParser, kind : ParseErrorKind, source : &str,
+               line : usize, column : usize, text : &str, detail : Option<String>) {
+    let desc = match kind {
+        ParseErrorKind::InvalidLine => "Line is neither a section header nor an option",
+        ParseErrorKind::ReadError => "Configuration source cannot be read",
+        ParseErrorKind::Config(k) => fe_error(k).description
+    };
+    cp.parse_errors.push(ParseError{ kind : kind, description : desc, detail : detail,
+                                     source : source.to_string(), line : line,
+                                     column : column, text : text.to_string() });
+}
+
+// record the error which stops reading a source, and hand it back
+fn rejected(cp : &mut ConfigParser, fe : FetchError, source : &str,
+            line : usize, column : usize, text : &str) -> FetchError {
+    parse_error(cp, ParseErrorKind::Config(fe.kind()), source, line, column, text, fe.detail());
+    fe
+}
+
+// how many errors stopping reading have been recorded
+fn rejections(cp : &ConfigParser) -> usize {
+    cp.parse_errors.iter().filter(|e| match e.kind {
+        ParseErrorKind::Config(_) => true,
+        _ => false
+    }).count()
+}
+
 impl Display for InterpString {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{}", self.get_raw())
@@ -126,1047 +507,4381 @@ impl PartialEq for InterpString {
 
 impl InterpString {
     pub fn new(s: &str) -> InterpString {
-        InterpString{ raw_string : s.to_string() }
+        InterpString{ values : vec![s.to_string()], comment : None, origin : Origin::Programmatic }
     }
 
+    pub fn with_comment(s: &str, c: Option<String>) -> InterpString {
+        InterpString{ values : vec![s.to_string()], comment : c, origin : Origin::Programmatic }
+    }
+
+    // any number of values, including none for a cleared option
+    fn from_values(vals: Vec<String>, c: Option<String>) -> InterpString {
+        InterpString{ values : vals, comment : c, origin : Origin::Programmatic }
+    }
+
+    /// Replaces all values with a single new one
     pub fn set(&mut self, s: &str) {
-        self.raw_string = s.to_string();
+        self.values = vec![s.to_string()];
     }
 
-    pub fn get_raw(&self) -> String {
-        self.raw_string.clone()
+    /// Adds another value to a multi-valued option
+    pub fn push(&mut self, s: &str, c: Option<String>) {
+        self.values.push(s.to_string());
+        self.comment = c;
     }
-    
-    fn expand_one(&self, oname : &str, text : &str,
-                  res : &String,
-                  sec : &str, option : &str,
-                  cp : &ConfigParser,
-                  expanded : &mut HashSet<String>) -> Result<String, FetchError> {
-        for s in expanded.iter() {
-            debug!("expanded contains \"{}\"", s)
-        }
-        if oname == option || expanded.contains(oname) {
-            warn!("Option {} has already been expanded or circular definition?", oname);
-            return Err(fe_error(FetchErrorKind::InterpolationCircularity))
-        }
-        
-        info!("Inserting {} into expanded set", oname);
-        expanded.insert(oname.to_string());
-        
-        match cp.get_interp(sec, oname, expanded) {
-            Ok(v) => Ok(res.replace(text, v.as_slice())),
-            Err(e) => {
-                warn!("Error in lookup for interpolation of {}:{}: {:?}",
-                      sec, oname, e);
-                
-                Err(if e.kind() == FetchErrorKind::InterpolationCircularity {e}
-                    else {fe_error(FetchErrorKind::InterpolationError)})
+
+    // add an appended value: another value of a multi-valued option,
+    // or else more text on the end of the last one
+    fn append(&mut self, s: &str, multi: bool) {
+        if multi || self.values.is_empty() {
+            self.values.push(s.to_string());
+        } else if ! s.is_empty() {
+            let last = self.values.last_mut().unwrap();
+            if ! last.is_empty() {
+                last.push(' ');
             }
+            last.push_str(s);
         }
     }
 
+    // replace just the last value
+    fn set_last(&mut self, s: &str) {
+        self.values.pop();
+        self.values.push(s.to_string());
+    }
+
+    pub fn get_raw(&self) -> String {
+        match self.values.last() {
+            Some(v) => v.clone(),
+            None => String::new()
+        }
+    }
+
+    /// Where the (last) value was set
+    pub fn origin(&self) -> Origin {
+        self.origin.clone()
+    }
+
+    /// All of the (uninterpolated) values, oldest first
+    pub fn values(&self) -> Vec<String> {
+        self.values.clone()
+    }
+
+    /// The inline comment which followed the value, if any
+    pub fn comment(&self) -> Option<String> {
+        self.comment.clone()
+    }
+    
     /// Interpolate any values in the string via the
     /// options inside the specified section
     pub fn get(&self, sec : &str, option : &str, cp : &ConfigParser,
                expanded : &mut HashSet<String>) -> Result<String, FetchError> {
-        let mut res = self.raw_string.clone();
+        self.get_resolved(sec, option, cp, expanded, &mut HashMap::new())
+    }
 
-        loop {
-            let mut done_cap = false;
-            loop {
-                let mut repl = res.clone();
-                match cp.i_re.captures(res.as_slice()) {
-                    Some(cap) => {
-                        match cap.at(1) {
-                            Some(t) => {
-                                match cap.at(2) {
-                                    Some(oname) => {
-                                        match self.expand_one(oname, t,
-                                                              &res,
-                                                              sec,
-                                                              option,
-                                                              cp,
-                                                              expanded) {
-                                            Ok(v) => {
-                                                repl = v;
-                                                done_cap = true;
-                                            },
-                                            Err(e) => {return Err(e);}
-                                        }
-                                    },
-                                    None => {
-                                        warn!("Capture for interpolation option \
-                                               found, but no matching text!");
-                                    }
-                                }
-                            },
-                            None => {
-                                warn!("Capture for interpolation found, \
-                                       but no matching text!");
-                            } // shouldn't really happen though
-                        }
-                    },
-                    None => {
-                        break; // no more captures
-                    }
-                }
-                
-                res = repl; // replace text, ready to try again
-            }
+    // as get, with the values of the options looked up so far
+    fn get_resolved(&self, sec : &str, option : &str, cp : &ConfigParser,
+                    expanded : &mut HashSet<String>,
+                    resolved : &mut HashMap<String, String>) -> Result<String, FetchError> {
+        self.interpolate(self.get_raw(), sec, option, cp, expanded, resolved)
+    }
 
-            // if we didn't do any replacements, then we exit
-            if ! done_cap {
-                break; // no captures to substitute
-            }
+    /// As `get`, but interpolating each of the values of a
+    /// multi-valued option
+    pub fn get_all(&self, sec : &str, option : &str, cp : &ConfigParser,
+                   expanded : &mut HashSet<String>) -> Result<Vec<String>, FetchError> {
+        let mut res = vec![];
+        let mut resolved = HashMap::new();
+        for v in self.values.iter() {
+            let mut exp = expanded.clone();
+            res.push(try!(self.interpolate(v.clone(), sec, option, cp, &mut exp, &mut resolved)));
         }
         Ok(res)
     }
+
+    fn interpolate(&self, raw : String, sec : &str, option : &str, cp : &ConfigParser,
+                   expanded : &mut HashSet<String>,
+                   resolved : &mut HashMap<String, String>) -> Result<String, FetchError> {
+        interpolate_refs(raw.as_slice(), option, expanded, resolved, &cp.opts.limits,
+                         |oname, exp, res| cp.get_interp(sec, oname, exp, res))
+    }
+}
+
+/// Which files `read` loaded, and which it could not open
+#[derive(Clone,Debug)]
+pub struct ReadReport {
+    /// the (absolute) paths of the files read, in order
+    pub loaded : Vec<Path>,
+    /// the paths of files which could not be opened, and why
+    pub failed : Vec<(Path, IoError)>
+}
+
+// a line of text kept by a lossless parser (with any continuation
+// lines), and what it holds
+struct DocLine {
+    raw : String,
+    kind : DocKind
+}
+
+enum DocKind {
+    // comments, blank lines, directives and anything malformed
+    Other,
+    // the header of a section
+    Header(String),
+    // an option (section, key)
+    Setting(String, String)
+}
+
+impl DocLine {
+    fn is_option(&self, section : &str, option : &str) -> bool {
+        match self.kind {
+            DocKind::Setting(ref s, ref o) => s.as_slice() == section && o.as_slice() == option,
+            _ => false
+        }
+    }
+
+    fn in_section(&self, section : &str) -> bool {
+        match self.kind {
+            DocKind::Header(ref s) | DocKind::Setting(ref s, _) => s.as_slice() == section,
+            DocKind::Other => false
+        }
+    }
+}
+
+/// A logical line, as built from one or more physical lines
+pub struct LogicalLine {
+    /// the line text, with continuations joined, and a trailing newline
+    pub text : String,
+    /// how many physical lines were consumed, comments included
+    pub lines : usize,
+    /// how many of those were comment lines before the text started
+    pub skipped : usize,
+    /// the physical lines exactly as they were read
+    pub raw : String
 }
 
 pub trait ContinuationReader {
+    /// Read a logical line (joining backslash continuations and
+    /// skipping comment lines) with the default `ParserOptions`
     fn read_continued_line(&mut self) -> IoResult<String>;
+    /// Read a logical line, treating comments as directed by `opts`
+    fn read_logical_line(&mut self, opts: &ParserOptions) -> IoResult<String>;
+    /// As `read_logical_line`, but also reporting how many physical
+    /// lines were read, so that callers can keep track of line numbers
+    fn read_numbered_line(&mut self, opts: &ParserOptions) -> IoResult<LogicalLine>;
+    /// As `read_numbered_line`, but telling a line over one of the
+    /// length limits in `opts` apart from other errors. `first` marks
+    /// the first line of a source, where a byte order mark is dropped.
+    fn read_limited_line(&mut self, opts: &ParserOptions,
+                         first: bool) -> Result<LogicalLine, LineError>;
+}
+
+/// Why `read_limited_line` could not read a line
+#[derive(Clone,Debug)]
+pub enum LineError {
+    /// The reader failed, or the line could not be decoded
+    Io(IoError),
+    /// A physical or logical line was over its length limit, as the
+    /// detail says
+    TooLong(String)
+}
+
+fn line_too_long(what : &str, max : usize) -> LineError {
+    LineError::TooLong(format!("{} line over {} bytes", what, max))
+}
+
+// read the bytes of a line, failing rather than reading a line of
+// more than max bytes (besides its newline) into memory
+fn read_bytes_within<B: Buffer>(b : &mut B, max : usize) -> Result<Vec<u8>, LineError> {
+    let mut bytes : Vec<u8> = vec![];
+    loop {
+        let (found, used) = match b.fill_buf() {
+            Ok(avail) => {
+                let (found, n) = match avail.iter().position(|&c| c == b'\n') {
+                    Some(i) => (true, i),
+                    None => (false, avail.len())
+                };
+                if bytes.len() + n > max {
+                    return Err(line_too_long("physical", max))
+                }
+                let used = if found { n + 1 } else { n };
+                bytes.push_all(&avail[..used]);
+                (found, used)
+            },
+            Err(ref e) if e.kind == IoErrorKind::EndOfFile && ! bytes.is_empty() => break,
+            Err(e) => return Err(LineError::Io(e))
+        };
+        b.consume(used);
+        if found {
+            break;
+        }
+    }
+    Ok(bytes)
+}
+
+// read a line, as `read_line` does, but decoding it as the options
+// direct and keeping to the line length limit. On the first line of a
+// source, a UTF-8 byte order mark is taken as one whatever the
+// encoding, and decoded as U+FEFF to be dropped from the text
+fn read_decoded_line<B: Buffer>(b : &mut B, opts : &ParserOptions,
+                                first : bool) -> Result<String, LineError> {
+    let mut bytes = match opts.limits.max_line_length {
+        Some(max) => try!(read_bytes_within(b, max)),
+        None => match b.read_until(b'\n') {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(LineError::Io(e))
+        }
+    };
+    let mut text = String::new();
+    if first && bytes.starts_with(b"\xef\xbb\xbf") {
+        bytes = bytes[3..].to_vec();
+        text.push('\u{feff}');
+    }
+    let decoded = match opts.encoding {
+        Encoding::Utf8Strict => match String::from_utf8(bytes) {
+            Ok(l) => Ok(l),
+            Err(_) => Err(LineError::Io(IoError { kind : IoErrorKind::InvalidInput,
+                                                  desc : "Line is not valid UTF-8",
+                                                  detail : None }))
+        },
+        Encoding::Utf8Lossy => Ok(String::from_utf8_lossy(bytes.as_slice()).into_owned()),
+        Encoding::Latin1 => Ok(bytes.iter().map(|&c| c as char).collect()),
+        Encoding::Windows1252 => Ok(bytes.iter().map(|&c| match c {
+            0x80...0x9f => WINDOWS_1252[(c - 0x80) as usize],
+            _ => c as char
+        }).collect())
+    };
+    text.push_str(try!(decoded).as_slice());
+    Ok(text)
+}
+
+// text without any byte order mark at its start, for the first line
+// of a source; elsewhere U+FEFF is just another character
+fn strip_bom(l : &str) -> &str {
+    if l.starts_with("\u{feff}") {
+        &l["\u{feff}".len()..]
+    } else {
+        l
+    }
+}
+
+// a physical line without its line ending (`\n`, `\r\n`, or a `\r`
+// at the end of the text), and whether it ended in a newline
+fn split_line_end(l : &str) -> (&str, bool) {
+    if l.ends_with("\r\n") {
+        (&l[..l.len()-2], true)
+    } else if l.ends_with("\n") {
+        (&l[..l.len()-1], true)
+    } else if l.ends_with("\r") {
+        (&l[..l.len()-1], false)
+    } else {
+        (l, false)
+    }
+}
+
+// the part of a line from a to b, borrowed from the text if the line
+// is, and taking over the line's own storage if it can
+fn cow_slice<'a>(line : Cow<'a, str>, a : usize, b : usize) -> Cow<'a, str> {
+    match line {
+        Cow::Borrowed(s) => Cow::Borrowed(&s[a..b]),
+        Cow::Owned(mut s) => {
+            if a == 0 {
+                s.truncate(b);
+                Cow::Owned(s)
+            } else {
+                Cow::Owned(s[a..b].to_string())
+            }
+        }
+    }
+}
+
+// the part of a logical line from a to b, borrowed from the text
+// if the line is
+fn ref_piece<'a>(line : &Cow<'a, str>, a : usize, b : usize) -> Cow<'a, str> {
+    match *line {
+        Cow::Borrowed(s) => Cow::Borrowed(&s[a..b]),
+        Cow::Owned(ref s) => Cow::Owned(s[a..b].to_string())
+    }
+}
+
+// what a physical line did for the logical line being joined
+enum Joined {
+    // the logical line goes on to the next physical line
+    More,
+    // the logical line is complete
+    Done,
+    // the text ended in the middle of a continued line
+    Dangling
+}
+
+// Joins physical lines into a logical line: comment lines before the
+// text are skipped, backslash continuations are joined (less the
+// leading whitespace of the lines they continue onto), and a comment
+// finishes off a continued line unless `comments_in_continuations` is
+// set. The buffered readers hand it lines they own, and `ConfigRef`
+// lines borrowed from its text, which stay borrowed where they can.
+struct LineJoiner<'a> {
+    text : Option<Cow<'a, str>>,
+    continuing : bool,
+    comments_in_continuations : bool,
+    // physical lines taken, and how many were comments before the text
+    lines : usize,
+    skipped : usize
+}
+
+impl<'a> LineJoiner<'a> {
+    fn new(opts : &ParserOptions) -> LineJoiner<'a> {
+        LineJoiner { text : None, continuing : false,
+                     comments_in_continuations : opts.comments_in_continuations,
+                     lines : 0, skipped : 0 }
+    }
+
+    // the length of the text joined so far
+    fn len(&self) -> usize {
+        match self.text {
+            Some(ref t) => t.len(),
+            None => 0
+        }
+    }
+
+    // take the next physical line, without its line ending; `newline`
+    // says whether it had one
+    fn push(&mut self, line : Cow<'a, str>, newline : bool) -> Joined {
+        self.lines += 1;
+        let comment = {
+            let tl = line.trim_left();
+            tl.starts_with("#") || tl.starts_with(";")
+        };
+        if comment {
+            if self.continuing && ! self.comments_in_continuations {
+                // a comment finishes off the continued line (and is
+                // itself ignored)
+                return Joined::Done
+            }
+            // ignore comment lines, indented or not
+            if ! self.continuing {
+                self.skipped += 1;
+            }
+            return Joined::More
+        }
+        let (end, more) = if line.ends_with("\\") {
+            if ! newline {
+                // the text ends on a continuation
+                return Joined::Dangling
+            }
+            (line.len() - 1, true)
+        } else {
+            (line.len(), false)
+        };
+        // strip leading whitespace from a continuing line
+        let start = if self.continuing {
+            let l : &str = &*line;
+            end - l[..end].trim_left().len()
+        } else {
+            0
+        };
+        let piece = cow_slice(line, start, end);
+        self.text = Some(match self.text.take() {
+            None => piece,
+            Some(t) => {
+                let mut joined = t.into_owned();
+                joined.push_str(piece.as_slice());
+                Cow::Owned(joined)
+            }
+        });
+        if more {
+            self.continuing = true;
+            Joined::More
+        } else {
+            Joined::Done
+        }
+    }
+
+    // the text joined
+    fn text(self) -> Cow<'a, str> {
+        self.text.unwrap_or(Cow::Borrowed(""))
+    }
 }
 
 impl<T:Buffer> ContinuationReader for T {
     fn read_continued_line(&mut self) -> IoResult<String> {
-        let mut result_line: String = "".to_string();
-        let mut continuing = false;
-        loop {
-            match self.read_line() {
-                Ok(l) => {
-                    let tr = l.as_slice();
-                    debug!("Read line: {}", tr.trim_right());
-                    let ll = tr.len();
-
-                    if ll == 0 {
-                        debug!("Read line with no newline");
-                        break;
-                    }
+        self.read_logical_line(&ParserOptions::new())
+    }
 
-                    if tr.starts_with("#") || tr.starts_with(";") {
-                        // ignore comment lines
-                        continue;
-                    }
+    fn read_logical_line(&mut self, opts: &ParserOptions) -> IoResult<String> {
+        self.read_numbered_line(opts).map(|l| l.text)
+    }
 
-                    // check for line which doesn't end with newline
-                    // (e.g. at end of file
-                    if ! tr.ends_with("\n") {
-                        let mut trl = tr;
-                        if tr.ends_with("\\") {
-                            // must be end of file, ending on continuation
-                            // (yuck) - signal end of line, and ignore any
-                            // data to this point
-                            return Err(standard_error(IoErrorKind::EndOfFile))
-                        }
-                        if continuing {
-                            trl = trl.trim_left();
-                        }
-                        result_line.push_str(trl);
-                        break; 
-                    }
+    fn read_numbered_line(&mut self, opts: &ParserOptions) -> IoResult<LogicalLine> {
+        match self.read_limited_line(opts, false) {
+            Ok(ll) => Ok(ll),
+            Err(LineError::Io(e)) => Err(e),
+            Err(LineError::TooLong(d)) => Err(IoError {
+                kind : IoErrorKind::OtherIoError,
+                desc : "Line is longer than the configured limit",
+                detail : Some(d) })
+        }
+    }
 
-                    if tr.ends_with("\\\n") {
-                        let mut trl = tr[..ll-2].to_string();
-                        if continuing {
-                            trl = trl.trim_left().to_string();
-                            // strip leading ws
-                            // from continuing line
-                        } else {
-                            continuing = true;
-                        }
-                        result_line.push_str(trl.as_slice());
+    fn read_limited_line(&mut self, opts: &ParserOptions,
+                         first: bool) -> Result<LogicalLine, LineError> {
+        let mut joiner = LineJoiner::new(opts);
+        let mut raw = String::new();
+        let max_logical = opts.limits.max_logical_line_length;
+        loop {
+            if let Some(max) = max_logical {
+                if joiner.len() > max {
+                    return Err(line_too_long("logical", max))
+                }
+            }
+            match read_decoded_line(self, opts, first && joiner.lines == 0) {
+                Ok(l) => {
+                    raw.push_str(l.as_slice());
+                    let l = if first && joiner.lines == 0 {
+                        strip_bom(l.as_slice())
                     } else {
-                        // no continuation - return line
-                        let mut trl = tr[..ll-1].to_string();
-                        if continuing {
-                            trl = trl.trim_left().to_string();
-                            // strip leading ws
-                            // from continuing line
-                        }
-
-                        result_line.push_str(trl.as_slice());
-                        break;
+                        l.as_slice()
+                    };
+                    let (body, newline) = split_line_end(l);
+                    debug!("Read line: {}", body);
+                    match joiner.push(Cow::Owned(body.to_string()), newline) {
+                        Joined::More => {},
+                        Joined::Done => break,
+                        // must be end of file, ending on continuation
+                        // (yuck) - signal end of line, and ignore any
+                        // data to this point
+                        Joined::Dangling =>
+                            return Err(LineError::Io(standard_error(IoErrorKind::EndOfFile)))
                     }
                 },
+                Err(LineError::Io(ref e)) if e.kind == IoErrorKind::EndOfFile &&
+                    joiner.lines > 0 && ! joiner.continuing => {
+                    // nothing but comments before the end: hand them
+                    // back as a blank line, so the text is not lost
+                    break;
+                },
                 Err(e) => {
                     debug!("Error pushed: {:?}", e);
                     return Err(e)
                 }
             }
         };
+        let (lines, skipped) = (joiner.lines, joiner.skipped);
+        let mut result_line = joiner.text().into_owned();
+        if let Some(max) = max_logical {
+            if result_line.len() > max {
+                return Err(line_too_long("logical", max))
+            }
+        }
+        // lines of nothing but whitespace count as blank
+        if result_line.trim().is_empty() {
+            result_line.truncate(0);
+        }
         // re-add a newline
         result_line.push('\n');
         debug!("Returning line: {}", result_line.trim_right());
-        Ok(result_line)
+        Ok(LogicalLine { text : result_line, lines : lines, skipped : skipped, raw : raw })
     }
 }
 
-// this should be returning Option<(String,Option<String>)> to cover
-// the case where the line is a simple "key" (without a '=' or ':')
-// value to the right of it. For the moment, if we get a key without
-// an attendant value, we set the value to an empty string, but this
-// isn't really the appropriate thing to do.
-fn get_captured_kv(c : regex::Captures) -> Option<(String,String)> {
-    if c.len() < 2 {
-        return None
-    }
-    debug!("Capture 1: {}",
-          match c.at(1) {
-              Some(t) => t,
-              _ => "None"
-          });
-
-    debug!("Capture 2: {}",
-          match c.at(2) {
-              Some(t) => t,
-              _ => "None"
-          });
-
-    debug!("Capture 3: {}",
-          match c.at(3) {
-              Some(t) => t,
-              _ => "None"
-          });
-
-    match c.at(1) {
-        Some(key) => match c.at(3) {
-            Some(val) => Some((key.to_string(), val.to_string())),
-            _ => Some((key.to_string(), String::from_str(""))),
-        },
-        _ => None,
-    }
+/// A piece of configuration text, as yielded by `Events`. Line
+/// numbers count physical lines, from 1. The text of an event is
+/// borrowed from the line it was read from where it can be, but
+/// `Events` reads its lines into storage of its own, so owns all its
+/// text.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Event<'a> {
+    /// A section header. Any git style subsection is in canonical
+    /// form, and with `explicit_inheritance` a `[child : parent]`
+    /// header is split into the name and its parent.
+    SectionStart { name : Cow<'a, str>, parent : Option<Cow<'a, str>>, line : usize },
+    /// An option, with any inline comment split off its value and
+    /// quotes removed, as the `ParserOptions` direct. `append` marks
+    /// a `key += value` line and `array` a `key[] = value` one; the
+    /// key has neither suffix.
+    Option { key : Cow<'a, str>, value : Cow<'a, str>, comment : Option<Cow<'a, str>>,
+             append : bool, array : bool, line : usize },
+    /// An indented line which continues the value of the option
+    /// before it (with `indent_continuation`)
+    Continuation { text : Cow<'a, str>, line : usize },
+    /// An include directive (with `allow_includes`), naming a file or
+    /// pattern, or a directory of fragments if `dir` is set
+    Include { path : Cow<'a, str>, dir : bool, line : usize },
+    /// A comment line, trimmed
+    Comment { text : Cow<'a, str>, line : usize },
+    /// A line with nothing but whitespace on it
+    Blank { line : usize },
+    /// A line which is none of the above, with the column (from 1)
+    /// at which its text starts
+    Invalid { text : Cow<'a, str>, line : usize, column : usize }
 }
 
-fn try_option_kv (cp : &mut ConfigParser, tl : &str, curr_sect : &String) {
-    match cp.option_kv(tl) {
-        Some((opt,val)) => {
-            if curr_sect.is_empty() {
-                warn!("Attempting to set option [{}, {}] outside of section - ignoring", opt, val);
-            } else {
-                let s = cp.sections.get_mut(curr_sect);
+// The line grammar's driver, shared by `Events` and `ConfigRef`: it
+// turns logical lines into events as the `ParserOptions` direct,
+// borrowing what it can from the line, and keeps the indent of the
+// last option, which continuation lines exceed.
+struct Lexer {
+    opts : ParserOptions,
+    opt_indent : Option<usize>
+}
 
-                match s {
-                    Some(ohash) => {
-                        ohash.insert(opt, InterpString::new(val.as_slice()));
-                    },
-                    None => {
-                        error!("Should not get this - \
-                                current section {} does not exist. Ignoring", curr_sect);
-                    }
+impl Lexer {
+    fn new(opts : &ParserOptions) -> Lexer {
+        Lexer { opts : opts.clone(), opt_indent : None }
+    }
+
+    // the part of a line from a to b, split from any inline comment
+    fn strip_comment<'a>(&self, ll : &Cow<'a, str>, a : usize, b : usize)
+                         -> (Cow<'a, str>, Option<Cow<'a, str>>) {
+        if self.opts.inline_comment_prefixes.is_empty() {
+            (ref_piece(ll, a, b), None)
+        } else {
+            let l : &str = &**ll;
+            let prefixes = self.opts.inline_comment_prefixes.as_slice();
+            let (v, c) = strip_inline_comment(&l[a..b], prefixes);
+            (Cow::Owned(v), c.map(|c| Cow::Owned(c)))
+        }
+    }
+
+    // the target of an include line: `include = path`, `!include path`,
+    // or their `include_dir` forms, and whether it is a directory
+    fn include_directive(&self, t : &str) -> Option<(String, bool)> {
+        for &(directive, dir) in [("!include_dir", true), ("!include", false)].iter() {
+            if t.starts_with(directive) {
+                let rest = &t[directive.len()..];
+                if rest.starts_with(" ") || rest.starts_with("\t") {
+                    return Some((rest.trim().to_string(), dir))
                 }
             }
-        },
-        None => {} // do nothing
+        }
+        let (k, v) = match scan_option_kv(t) {
+            Some(((ka, kb), (va, vb))) => (&t[ka..kb], &t[va..vb]),
+            None => return None
+        };
+        match k {
+            "include" | "include_dir" => {
+                let path = if self.opts.inline_comment_prefixes.is_empty() {
+                    v.to_string()
+                } else {
+                    strip_inline_comment(v, self.opts.inline_comment_prefixes.as_slice()).0
+                };
+                if path.is_empty() {
+                    None
+                } else {
+                    Some((path, k == "include_dir"))
+                }
+            },
+            _ => None
+        }
     }
-}
 
-fn from_reader_helper<T: ContinuationReader>(cp : &mut ConfigParser, r : &mut T) {
-    let mut curr_sect = "".to_string();
+    // the event for a logical line (which is not a comment) starting
+    // at the given line
+    fn lex<'a>(&mut self, ll : Cow<'a, str>, line : usize) -> Event<'a> {
+        let (start, end) = {
+            let tl = ll.trim_right();
+            (tl.len() - tl.trim_left().len(), tl.len())
+        };
+        let continues = match self.opt_indent {
+            Some(oi) => self.opts.indent_continuation && start > oi,
+            None => false
+        };
+        if start == end {
+            Event::Blank{ line : line }
+        } else if continues {
+            let (text, _) = self.strip_comment(&ll, start, end);
+            Event::Continuation{ text : text, line : line }
+        } else {
+            self.lex_line(&ll, start, end, line)
+        }
+    }
 
-    loop {
-        match r.read_continued_line() {
-            Ok(l) => {
-                let tl = l.trim_right();
-                match cp.section_name(tl) {
-                    Some(s) => {
-                        curr_sect = s.to_string();
-                        if cp.sections.contains_key(s.as_slice()) {
-                            continue
-                        } // ignore repeat section
-                        let p : HashMap<String, InterpString> = HashMap::new();
-                        cp.sections.insert(s, p);
-                    },
-                    None => {
-                        try_option_kv(cp, tl, &curr_sect);
+    // the event for a line which is not blank or a continuation, whose
+    // text runs from a to b
+    fn lex_line<'a>(&mut self, ll : &Cow<'a, str>, a : usize, b : usize, line : usize)
+                    -> Event<'a> {
+        self.opt_indent = None;
+        let l : &str = &**ll;
+        let t = &l[a..b];
+        if let Some((na, nb)) = scan_section_name(t) {
+            // the name is borrowed unless making it canonical changes it
+            let canon = canonical_section(&t[na..nb]);
+            let name = if canon.as_slice() == &t[na..nb] {
+                ref_piece(ll, a + na, a + nb)
+            } else {
+                Cow::Owned(canon)
+            };
+            let (name, parent) = if self.opts.explicit_inheritance {
+                let (n, p) = split_parent(name.as_slice());
+                (Cow::Owned(n), p.map(|p| Cow::Owned(p)))
+            } else {
+                (name, None)
+            };
+            return Event::SectionStart{ name : name, parent : parent, line : line }
+        }
+        if self.opts.allow_includes {
+            if let Some((path, dir)) = self.include_directive(t) {
+                return Event::Include{ path : Cow::Owned(path), dir : dir, line : line }
+            }
+        }
+        match scan_option_kv(t) {
+            Some(((ka, kb), (va, vb))) => {
+                let (val, comment) = self.strip_comment(ll, a + va, a + vb);
+                let value = if self.opts.quoted_values {
+                    match unquote_value(val.as_slice()) {
+                        Some(uq) => Cow::Owned(uq),
+                        None => val
                     }
-                    
-                }
+                } else {
+                    val
+                };
+                let key = ref_piece(ll, a + ka, a + kb);
+                let (key, append) = if self.opts.append_operator && key.ends_with("+") {
+                    let k = key[..key.len()-1].trim_right().to_string();
+                    (Cow::Owned(k), true)
+                } else {
+                    (key, false)
+                };
+                // `key[]` is just another way of adding to `key`
+                let (key, array) = if self.opts.multi_value && key.ends_with("[]") {
+                    let k = key[..key.len()-2].trim_right().to_string();
+                    (Cow::Owned(k), true)
+                } else {
+                    (key, false)
+                };
+                self.opt_indent = Some(a);
+                Event::Option{ key : key, value : value, comment : comment,
+                               append : append, array : array, line : line }
             },
-            Err(e) => {
-                match e.kind {
-                    IoErrorKind::EndOfFile => {
-                    },
-                    _ => {
-                        error!("Reader error on parser init: {:?}", e)
-                    }
-                }
-                break;
-            }
+            None => Event::Invalid{ text : ref_piece(ll, a, b), line : line, column : a + 1 }
         }
     }
 }
 
-fn abspath(p: &Path) -> IoResult<Path> {
-    match p.is_absolute() {
-        true => Ok(p.clone()),
-        _ => match env::current_dir() {
-            Ok(mut cwd) => {cwd.push(p); Ok(cwd)},
-            Err(e) => Err(e)
-        }
-    }
+///
+/// An iterator over the `Event`s in configuration text, read a
+/// logical line at a time from a `ContinuationReader`. This is what
+/// `ConfigParser` is built from, and lets other code examine text
+/// (or pick out just the parts of it that it wants) without building
+/// a parser. Iteration ends at the end of the text, or after a read
+/// error has been yielded; a line over the length limits of the
+/// options is a `LineError::TooLong` one.
+///
+/// # Example
+///
+/// ```
+/// use std::old_io::MemReader;
+/// use conparse::conparse::{Event,Events,ParserOptions};
+///
+/// let mut r = MemReader::new(b"[a]\nx = 1\n[b]\n# the one\ny = 2\n".to_vec());
+/// let opts = ParserOptions::new();
+/// let mut in_b = false;
+/// for ev in Events::new(&mut r, &opts) {
+///     match ev.unwrap() {
+///         Event::SectionStart{ name, .. } => in_b = name.as_slice() == "b",
+///         Event::Option{ ref key, ref value, line, .. } if in_b => {
+///             assert_eq!((key.as_slice(), value.as_slice(), line), ("y", "2", 5));
+///         },
+///         _ => {}
+///     }
+/// }
+/// ```
+///
+pub struct Events<'a, T: 'a> {
+    reader : &'a mut T,
+    lexer : Lexer,
+    // physical lines read so far
+    lineno : usize,
+    // events read but not yet yielded, with their text
+    pending : Vec<(Event<'static>, String)>,
+    // the text of the last event yielded
+    raw : String,
+    done : bool
 }
 
-impl ConfigParser {
-    ///
-    /// Creates an empty ConfigParser with default key,value pairs
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let mut cp = ConfigParser::new(&[("host","localhost"), ("port","22"), ("protocol","tcp")]);
-    /// ```
-    ///
-    pub fn new(kvdefaults : &[(&str, &str)]) -> ConfigParser {
-        let mut df = HashMap::new();
-        for &(k,v) in kvdefaults.iter() {
-            df.insert(k.to_string(), v.to_string());
-        }
-        // make these regex macros once it's not experimental
-        // unwrap() in init code == teh suck
-        let sect_re = Regex::new(r"^\[\s*(\w+)\s*\](\s*[#;].*)?$").unwrap();
-        let option_re = Regex::new(r"^(\w+)(\s*[:=]\s*(.*))?$").unwrap();
-        let interp_re = Regex::new(r"(%\(\s*(\w+)\s*\)s)").unwrap();
-        let sects : HashMap<String, Props> = HashMap::new();
-        ConfigParser { defaults: df, sections : sects,
-                       s_re: sect_re, o_re : option_re, i_re : interp_re }
+impl<'a, T: ContinuationReader> Events<'a, T> {
+    pub fn new(reader : &'a mut T, opts : &ParserOptions) -> Events<'a, T> {
+        Events { reader : reader, lexer : Lexer::new(opts), lineno : 0, pending : vec![],
+                 raw : String::new(), done : false }
     }
 
-    //
-    // Strongly suspect (ie, know) that there's way too much
-    // mutability in here. Most of the time I just want to pass
-    // a slice of readers into a function. The readers will be
-    // mutated, but the slice itself should not be, so passing as
-    // &mut[ &mut T ] seems wrong.
-    //
-
-    ///
-    /// Create a new ConfigParser from a slice of `Reader`s which can
-    /// implement the `ContinuationReader` trait. In theory you could
-    /// pass a slice of ContinuationReader trait objects, but I need
-    /// to find out how to make them implement the Sized trait
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// #![feature(path)]
-    /// #![feature(io)]
-    /// use conparse::conparse::{ConfigParser};
-    /// use std::old_io::{BufferedReader,File};
-    /// use std::old_path::Path;
-    ///
-    /// fn open_files(fs : &[ &str ]) -> Vec<BufferedReader<File>> {
-    ///     fs.iter().filter_map(|&p| match File::open(&Path::new(p)) {
-    ///         Ok(f) => Some(BufferedReader::new(f)),
-    ///         Err(_) => None
-    ///     }).collect()
-    /// }
-    ///
-    /// let mut of = open_files(&["/etc/myapp/myconfig.txt",
-    ///                           "/usr/local/etc/myapp/extra-config.txt"]);
-    /// let mut mof : Vec<&mut BufferedReader<File>> = of.iter_mut().collect();
-    /// let cp = ConfigParser::from_readers(mof.as_mut_slice(), &[("host", "localhost")]);
-    /// ```
-    ///
-    pub fn from_readers<T: ContinuationReader>(rs : &mut[ &mut T ],
-                                               kvdefaults : &[(&str, &str)]) -> ConfigParser {
-        let mut cp = ConfigParser::new(kvdefaults);
-        for r in rs.iter_mut() {
-            from_reader_helper(&mut cp, *r)
-        }
-        cp
+    /// The text of the last event yielded, exactly as it was read
+    /// (line endings included)
+    pub fn raw(&self) -> &str {
+        self.raw.as_slice()
     }
 
-    ///
-    /// Create a new ConfigParser from a string specification
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let cp = ConfigParser::from_str(
-    ///          "[myapp]\n log_level = DEBUG", &[("log_level","WARN")]);
-    /// ```
-    ///
-    pub fn from_str(s: &str, kvdefaults : &[(&str, &str)]) -> ConfigParser {
-        let mut v = MemReader::new(s.as_bytes().to_vec());
-        ConfigParser::from_readers(&mut[&mut v], kvdefaults)
+    /// How many physical lines have been read so far
+    pub fn line(&self) -> usize {
+        self.lineno
     }
 
-    ///
-    /// Create a new ConfigParser from a list of string contents
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let cp = ConfigParser::from_strs(&["[myapp]\n log_level = DEBUG",
-    ///                 "[global]\ngreeting = Hello\n"], &[("log_level","INFO")]);
-    /// ```
-    ///
-    pub fn from_strs(ss: &[ &str ], kvdefaults : &[(&str, &str)]) -> ConfigParser {
-
-        let mut v = vec![];
-        for s in ss.iter() {
-            v.push(MemReader::new(s.as_bytes().to_vec()));
+    // queue up the events of a logical line: a comment for each comment
+    // line before it, then the line itself (if there is one)
+    fn lex(&mut self, ll : LogicalLine) {
+        let (lead, body) = split_lines(ll.raw.as_slice(), ll.skipped);
+        let mut rest = lead.as_slice();
+        let mut line = self.lineno;
+        while ! rest.is_empty() {
+            let end = match rest.find('\n') {
+                Some(i) => i + 1,
+                None => rest.len()
+            };
+            line += 1;
+            let l = if line == 1 { strip_bom(&rest[..end]) } else { &rest[..end] };
+            let (text, _) = split_line_end(l);
+            self.pending.push((Event::Comment{ text : Cow::Owned(text.trim().to_string()),
+                                               line : line },
+                               rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+        let this_line = self.lineno + ll.skipped + 1;
+        self.lineno += ll.lines;
+        if body.is_empty() {
+            return
         }
-        let mut v1 : Vec<&mut MemReader> = v.iter_mut().collect();
-        ConfigParser::from_readers(v1.as_mut_slice(), kvdefaults)
+        let ev = self.lexer.lex(Cow::Owned(ll.text), this_line);
+        self.pending.push((ev, body));
     }
+}
 
+impl<'a, T: ContinuationReader> Iterator for Events<'a, T> {
+    type Item = Result<Event<'static>, LineError>;
 
-    ///
-    /// Create a new ConfigParser from reading a list of files
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let cp = ConfigParser::from_files(&["/etc/myapp/config.txt",
-    ///                       "~/.myapp.cfg"], &[("log_level","INFO")]);
-    /// ```
-    ///
-    pub fn from_files(ss : &[ &str ], kvdefaults : &[(&str, &str)]) -> ConfigParser {
-        let mut v = vec![];
-        for s in ss.iter() {
-            let p = Path::new(*s);
-            let exp_p = match expand_homedir(&p) {
-                Ok(ep) => ep,
-                Err(e) => {
-                    error!("Cannot expand user homedir of {} : {}", p.display(), e);
-                    p.clone()
-                }
-            };
-            let abs_p = match abspath(&exp_p) {
-                Ok(ap) => ap,
-                Err(e) => {
-                    error!("Cannot make absolute directory of {} : {}", p.display(), e);
-                    exp_p.clone()
-                }
-            };
-
-            match File::open(&abs_p) {
-                Ok(f) => {
-                    v.push(BufferedReader::new(f))
+    fn next(&mut self) -> Option<Result<Event<'static>, LineError>> {
+        loop {
+            if ! self.pending.is_empty() {
+                let (ev, raw) = self.pending.remove(0);
+                self.raw = raw;
+                return Some(Ok(ev))
+            }
+            if self.done {
+                return None
+            }
+            match self.reader.read_limited_line(&self.lexer.opts, self.lineno == 0) {
+                Ok(ll) => self.lex(ll),
+                Err(LineError::Io(ref e)) if e.kind == IoErrorKind::EndOfFile => {
+                    self.done = true;
+                    self.raw.truncate(0);
                 },
                 Err(e) => {
-                    error!("Cannot open path {} for config: {:?}", *s, e);
+                    self.done = true;
+                    self.raw.truncate(0);
+                    return Some(Err(e))
                 }
             }
         }
-        let mut v1 : Vec<&mut BufferedReader<File>>  = v.iter_mut().collect();
-        
-        ConfigParser::from_readers(v1.as_mut_slice(), kvdefaults)
     }
+}
 
-    ///
-    /// Create a new ConfigParser from reading a file
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let cp = ConfigParser::from_file("/etc/myapp/config.txt", &[("log_level","INFO")]);
-    /// ```
-    ///
-    pub fn from_file(s : &str, kvdefaults : &[(&str, &str)]) -> ConfigParser {
-        ConfigParser::from_files(&[ s ], kvdefaults)
+// The line grammar. Section names follow Python: anything other than
+// ']', with surrounding whitespace trimmed, and the header may be
+// followed by a comment. These work on trimmed lines, and give the
+// positions of what they find, so that callers can borrow or copy it.
+
+// the name of a section header line, as the start and end of it
+fn scan_section_name(tl : &str) -> Option<(usize, usize)> {
+    if ! tl.starts_with("[") {
+        return None
+    }
+    let close = match tl.find(']') {
+        Some(i) => i,
+        None => return None
+    };
+    let after = tl[close+1..].trim_left();
+    if ! after.is_empty() && ! after.starts_with("#") && ! after.starts_with(";") {
+        return None
+    }
+    let inner = &tl[1..close];
+    let name = inner.trim();
+    if name.is_empty() {
+        return None
     }
+    let a = 1 + inner.len() - inner.trim_left().len();
+    Some((a, a + name.len()))
+}
 
-    pub fn to_writer(&self, w: &mut Writer) -> IoResult<()> {
-        let mut ss : Vec<&String> = self.sections().collect();
-        ss.sort();
+// the key and value of an option line, as where each starts and
+// ends. Keys are anything up to the first delimiter (again trimmed),
+// but may not start with '[', so that a mangled section header is
+// not mistaken for an option. A key without a delimiter after it
+// gets an empty value, which isn't really the appropriate thing to
+// do, but is what a bare `skip_init` line has always meant.
+fn scan_option_kv(tl : &str) -> Option<((usize, usize), (usize, usize))> {
+    let (key, value) = match tl.find(|c: char| c == ':' || c == '=') {
+        Some(i) => {
+            let rest = &tl[i+1..];
+            (tl[..i].trim_right(), (tl.len() - rest.trim_left().len(), tl.len()))
+        },
+        None => (tl, (tl.len(), tl.len()))
+    };
+    if key.is_empty() || key.starts_with("[") {
+        return None
+    }
+    Some(((0, key.len()), value))
+}
 
-        for s in ss.iter() {
-            match write!(w, "[{}]\n", s) {
-                Ok(_) => {} // continue
-                Err(_) =>
-                    return Err(
-                        IoError { 
-                            kind: IoErrorKind::ResourceUnavailable,
-                            desc: "Internal ConfigParser write error",
-                            detail:
-                            Some("Internal ConfigParser error: \
-                                  section not found during writing section"
-                                 .to_string())})
-            }
-            match self.options(s.as_slice()) {
-                Ok(o_raw) => {
-                    // want to sort the options
-                    let mut o : Vec<(&String,&InterpString)> = o_raw.collect();
-                    o.sort_by(|&(k1,_), &(k2,_)| k1.cmp(k2));
-
-                    for &(k,v) in o.iter() {
-                        match write!(w, "{} : {}\n", k, v) {
-                            Ok(_) => {},
-                            Err(_) =>
-                                return Err(
-                                    IoError {
-                                        kind: IoErrorKind::ResourceUnavailable,
-                                        desc: "Internal ConfigParser write error",
-                                        detail:
-                                        Some("Internal ConfigParser error: \
-                                              option not found during writing"
-                                             .to_string())})
-                        }
-                    }
-                },
-                Err(_) =>
-                    return Err(IoError { kind: IoErrorKind::ResourceUnavailable,
-                                         desc: "Internal ConfigParser write error",
-                                         detail:
-                                         Some("Internal ConfigParser error: \
-                                               unable to find options during writing"
-                                              .to_string())})
+// the first `%(name)s` reference in a value: where it starts and
+// ends, and the (trimmed) name in it
+fn find_interp(s : &str) -> Option<(usize, usize, &str)> {
+    let mut from = 0;
+    while let Some(i) = s[from..].find("%(") {
+        let start = from + i;
+        if let Some(j) = s[start+2..].find(')') {
+            let close = start + 2 + j;
+            let name = s[start+2..close].trim();
+            if ! name.is_empty() && s[close+1..].starts_with("s") {
+                return Some((start, close + 2, name))
             }
-            // blank line at end of each section
-            match write!(w, "\n") {
-                Ok(_) => {} // continue
-                Err(_) =>
-                    return Err(IoError { kind: IoErrorKind::ResourceUnavailable,
-                                         desc: "Internal ConfigParser write error",
-                                         detail: Some("Internal ConfigParser \
-                                                       error during writing"
-                                                      .to_string())})
-            }            
         }
-        Ok(()) // return success unit val
+        from = start + 2;
     }
+    None
+}
 
-    // convenience method for spitting to file
-    pub fn to_file(&self, fpath: &str) -> IoResult<()> {
-        let p = Path::new(fpath);
-        match File::open_mode(&p, Open, ReadWrite) {
-            Ok(mut f) => self.to_writer(&mut f),
-            Err(e) => {
-                error!("Unable to write to file {} : {}", fpath, e);
-                Err(e)
+// substitute the `%(name)s` references in a value in one pass,
+// looking each name up with `lookup`. `expanded` holds the names
+// being expanded, to catch circular definitions and keep to the
+// depth limit, and `resolved` the values of the names looked up
+// already in the same fetch: each is looked up only once however
+// many values refer to it, so that options shared by many others
+// cost no more than the rest.
+fn interpolate_refs<F>(raw : &str, option : &str, expanded : &mut HashSet<String>,
+                       resolved : &mut HashMap<String, String>, limits : &Limits,
+                       mut lookup : F) -> Result<String, FetchError>
+    where F : FnMut(&str, &mut HashSet<String>,
+                    &mut HashMap<String, String>) -> Result<String, FetchError> {
+    let mut res = String::new();
+    let mut rest = raw;
+    while let Some((a, b, oname)) = find_interp(rest) {
+        res.push_str(&rest[..a]);
+        let known = match resolved.get(oname) {
+            Some(v) => {
+                res.push_str(v.as_slice());
+                true
+            },
+            None => false
+        };
+        if ! known {
+            if oname == option || expanded.contains(oname) {
+                warn!("Option {} has already been expanded or circular definition?", oname);
+                return Err(fe_error(FetchErrorKind::InterpolationCircularity))
+            }
+            info!("Inserting {} into expanded set", oname);
+            expanded.insert(oname.to_string());
+            if let Some(max) = limits.max_interpolation_depth {
+                if expanded.len() > max {
+                    return Err(fe_error_detail(FetchErrorKind::LimitExceeded,
+                                               format!("interpolation of {} nested over {} deep",
+                                                       option, max)))
+                }
             }
+            let v = match lookup(oname, expanded, resolved) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Error in lookup for interpolation of {}: {:?}", oname, e);
+                    return Err(match e.kind() {
+                        FetchErrorKind::InterpolationCircularity |
+                        FetchErrorKind::LimitExceeded => e,
+                        _ => fe_error(FetchErrorKind::InterpolationError)
+                    })
+                }
+            };
+            expanded.remove(oname);
+            res.push_str(v.as_slice());
+            resolved.insert(oname.to_string(), v);
         }
+        try!(check_value_length(res.as_slice(), option, limits));
+        rest = &rest[b..];
     }
+    res.push_str(rest);
+    try!(check_value_length(res.as_slice(), option, limits));
+    Ok(res)
+}
 
-    // convenience method for spitting to a string
-    pub fn to_string(&self) -> IoResult<String> {
-        let mut w = MemWriter::new();
-        match self.to_writer(&mut w) {
-            Ok(_) => {
-                let s = String::from_utf8(w.into_inner());
-                match s {
-                    Ok(ret) => Ok(ret),
-                    Err(_) =>
-                        return Err(
-                            IoError {
-                                kind: IoErrorKind::ResourceUnavailable,
-                                desc: "Internal ConfigParser write error",
-                                detail: Some("Internal ConfigParser error during UTF-8 translation"
-                                             .to_string())})
-                }
-            },
-            Err(e) => {
-                error!("Unable to write to string : {}", e);
-                Err(e)
-            }
+fn check_value_length(v : &str, option : &str, limits : &Limits) -> Result<(), FetchError> {
+    match limits.max_value_length {
+        Some(max) if v.len() > max =>
+            Err(fe_error_detail(FetchErrorKind::LimitExceeded,
+                                format!("value of {} over {} bytes", option, max))),
+        _ => Ok(())
+    }
+}
+
+// refuse to let an option read take one more value, or more text on
+// the end of its last, than the limits allow
+fn check_growth(v : &InterpString, more : &str, multi : bool, option : &str,
+                limits : &Limits) -> Result<(), FetchError> {
+    if multi {
+        match limits.max_values {
+            Some(max) if v.values.len() >= max =>
+                Err(fe_error_detail(FetchErrorKind::LimitExceeded,
+                                    format!("option {} has over {} values", option, max))),
+            _ => Ok(())
+        }
+    } else {
+        let len = v.values.last().map_or(0, |l| l.len() + 1) + more.len();
+        match limits.max_value_length {
+            Some(max) if len > max =>
+                Err(fe_error_detail(FetchErrorKind::LimitExceeded,
+                                    format!("value of {} over {} bytes", option, max))),
+            _ => Ok(())
         }
     }
+}
 
-    fn section_name(&self, s: &str) -> Option<String> {
-        match self.s_re.captures(s.trim()) {
-            Some(c) =>
-                match c.at(1) {
-                    Some(cs) => Some(cs.to_string()),
-                    _ => None
-                },
-            _ => None
+fn starts_with_prefix(s: &str, prefixes: &[String]) -> bool {
+    prefixes.iter().any(|p| ! p.is_empty() && s.starts_with(p.as_slice()))
+}
+
+// What a character of a value is, for finding inline comments
+enum Scanned {
+    // the start of a comment
+    Comment,
+    // a backslash, escaping the character after it
+    Escape,
+    // an escaped character, and whether its backslash is kept (it is
+    // dropped from a comment prefix outside double quotes)
+    Escaped(bool),
+    // anything else
+    Plain
+}
+
+// The state of a scan along a value, as it is read: whether inside
+// double quotes, after a backslash, or at the start of a word (where a
+// comment may begin). Reading and writing share it, so that what is
+// written reads back the same
+struct CommentScan {
+    in_quote : bool,
+    escaped : bool,
+    prev_ws : bool
+}
+
+impl CommentScan {
+    fn new() -> CommentScan {
+        CommentScan { in_quote : false, escaped : false, prev_ws : true }
+    }
+
+    // step over c, at the start of rest
+    fn step(&mut self, rest : &str, c : char, prefixes : &[String]) -> Scanned {
+        if self.escaped {
+            self.escaped = false;
+            self.prev_ws = false;
+            return Scanned::Escaped(self.in_quote || ! starts_with_prefix(rest, prefixes))
         }
+        if ! self.in_quote && self.prev_ws && starts_with_prefix(rest, prefixes) {
+            return Scanned::Comment
+        }
+        match c {
+            '\\' => {
+                self.escaped = true;
+                self.prev_ws = false;
+                return Scanned::Escape
+            },
+            '"' => self.in_quote = ! self.in_quote,
+            _ => {}
+        }
+        self.prev_ws = c.is_whitespace();
+        Scanned::Plain
     }
+}
 
+// Split a value into the value proper and any trailing inline
+// comment. Prefixes inside double quotes don't count, and a prefix
+// escaped with a backslash is kept in the value (minus the backslash)
+fn strip_inline_comment(v: &str, prefixes: &[String]) -> (String, Option<String>) {
+    let mut val = String::new();
+    let mut scan = CommentScan::new();
 
-    fn option_kv(&self, s: &str) -> Option<(String,String)> {
-        match self.o_re.captures(s.trim()) {
-            Some(c) => {
-                get_captured_kv(c)},
-            _ => None
+    for (i, c) in v.char_indices() {
+        match scan.step(&v[i..], c, prefixes) {
+            Scanned::Comment => return (val.trim_right().to_string(), Some(v[i..].to_string())),
+            Scanned::Escape => {},
+            Scanned::Escaped(keep) => {
+                if keep {
+                    val.push('\\');
+                }
+                val.push(c);
+            },
+            Scanned::Plain => val.push(c)
         }
     }
+    if scan.escaped {
+        val.push('\\');
+    }
+    (val, None)
+}
 
-    ///
-    /// Adds an (empty) section to a mutable `ConfigParser` object.
-    /// If the section already exists, a
-    /// `FetchError::DuplicateSection` error is returned, otherwise
-    /// `()` is returned as an `Ok` value.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let mut cp = ConfigParser::new(&[]);
-    /// assert!(cp.add_section("foo").is_ok());
-    /// assert!(cp.add_section("foo").is_err()); // duplicating section
-    /// ```
-    pub fn add_section(&mut self, s : &str) -> Result<(), FetchError> {
-        let sec = s.to_string();
-        match self.sections.entry(sec) {
-            Entry::Occupied(_) => Err(fe_error(FetchErrorKind::DuplicateSection)),
-            Entry::Vacant(v) => {
-                v.insert(HashMap::new());
-                Ok(())
-            }
+// The reverse of strip_inline_comment: backslash any prefix in the
+// value which would otherwise be read back as the start of a comment
+fn escape_inline_comment(v: &str, prefixes: &[String]) -> String {
+    let mut res = String::new();
+    let mut scan = CommentScan::new();
+
+    for (i, c) in v.char_indices() {
+        if let Scanned::Comment = scan.step(&v[i..], c, prefixes) {
+            // read back as an escaped character
+            res.push('\\');
+            scan.prev_ws = false;
         }
+        res.push(c);
     }
+    res
+}
 
-    /// Zaps a section from a `ConfigParser`. If the section does not
-    /// exist, a `FetchError::NoSuchSection` is returned as error.
-    /// Otherwise `()` is returned as an `Ok` value. Note that this
-    /// deletes all options contained within the section.
+// Decode a double quoted value. Returns None if the value isn't a
+// well formed quoted string (in which case it should be used as is)
+fn unquote_value(v: &str) -> Option<String> {
+    if ! v.starts_with("\"") {
+        return None
+    }
+    let mut res = String::new();
+    let mut chars = v[1..].chars();
+    loop {
+        match chars.next() {
+            None => return None, // no closing quote
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+                Some('n') => res.push('\n'),
+                Some('r') => res.push('\r'),
+                Some('t') => res.push('\t'),
+                Some('0') => res.push('\0'),
+                Some('\\') => res.push('\\'),
+                Some('"') => res.push('"'),
+                Some('\'') => res.push('\''),
+                Some('u') => {
+                    let mut code = 0u32;
+                    for _ in 0..4 {
+                        match chars.next().and_then(|h| h.to_digit(16)) {
+                            Some(d) => code = code * 16 + d as u32,
+                            None => return None
+                        }
+                    }
+                    match char::from_u32(code) {
+                        Some(c) => res.push(c),
+                        None => return None
+                    }
+                },
+                _ => return None
+            },
+            Some(c) => res.push(c)
+        }
+    }
+    // nothing but whitespace may follow the closing quote
+    if chars.all(|c| c.is_whitespace()) {
+        Some(res)
+    } else {
+        None
+    }
+}
+
+// The reverse of unquote_value
+fn quote_value(v: &str) -> String {
+    let mut res = "\"".to_string();
+    for c in v.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '"' => res.push_str("\\\""),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            '\0' => res.push_str("\\0"),
+            c if c.is_control() => res.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+            c => res.push(c)
+        }
+    }
+    res.push('"');
+    res
+}
+
+// Split a `child : parent` section name at the first colon which is
+// not inside a quoted subsection
+fn split_parent(name: &str) -> (String, Option<String>) {
+    let mut in_quote = false;
+    let mut escaped = false;
+    for (i, c) in name.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_quote => escaped = true,
+            '"' => in_quote = ! in_quote,
+            ':' if ! in_quote => {
+                let child = name[..i].trim();
+                let parent = name[i+1..].trim();
+                if child.is_empty() {
+                    break;
+                }
+                return (canonical_section(child),
+                        if parent.is_empty() { None } else { Some(canonical_section(parent)) })
+            },
+            _ => {}
+        }
+    }
+    (name.to_string(), None)
+}
+
+// a section name, with any subsection in canonical form
+fn canonical_section(s: &str) -> String {
+    let (name, sub) = ConfigParser::split_section_key(s);
+    ConfigParser::section_key(name.as_slice(), sub.as_ref().map(|s| s.as_slice()))
+}
+
+// the values that the first `key += ...` of a section builds on:
+// those the option would inherit or default to
+fn append_base(cp : &ConfigParser, sect : &str, opt : &str) -> Vec<String> {
+    match cp.inherited(sect, opt) {
+        Ok(Some(v)) => return v.values(),
+        _ => {} // any inheritance loop is reported on fetching
+    }
+    match cp.default_sect.get(opt) {
+        Some(v) => v.values(),
+        None => match cp.defaults.get(opt) {
+            Some(d) => vec![d.clone()],
+            None => vec![]
+        }
+    }
+}
+
+// set an option read at the given line of a source, returning its
+// name if it has been kept. Values which would grow an option past
+// the limits are refused
+fn set_option(cp : &mut ConfigParser, curr_sect : &String, opt : String, val : String,
+              comment : Option<String>, append : bool, array : bool,
+              source : &str, line : usize) -> Result<Option<String>, FetchError> {
+    // options before any section go into the default section, if
+    // they are kept at all
+    let global = curr_sect.is_empty();
+    if global && cp.opts.global_options != GlobalOptions::Defaults {
+        warn!("Attempting to set option [{}, {}] outside of section - ignoring", opt, val);
+        return Ok(None)
+    }
+    let multi = cp.opts.multi_value;
+    let limits = cp.opts.limits.clone();
+    let origin = Origin::Source(source.to_string(), line);
+    if append {
+        // the option is appended to where it is, once it has a copy
+        // of what it inherits
+        if ! global_or_section(cp, curr_sect).contains_key(opt.as_slice()) {
+            let base = append_base(cp, curr_sect.as_slice(), opt.as_slice());
+            global_or_section(cp, curr_sect).insert(opt.clone(),
+                                                    InterpString::from_values(base, None));
+        }
+        if let Some(v) = global_or_section(cp, curr_sect).get_mut(opt.as_slice()) {
+            try!(check_growth(v, val.as_slice(), multi, opt.as_slice(), &limits));
+            v.append(val.as_slice(), multi);
+            v.comment = comment;
+            v.origin = origin;
+        }
+    } else {
+        // an empty `key =` clears a multi-valued option
+        let reset = multi && cp.opts.append_operator && !array && val.is_empty();
+        let ohash = global_or_section(cp, curr_sect);
+        if multi && !reset && ohash.contains_key(opt.as_slice()) {
+            if let Some(v) = ohash.get_mut(opt.as_slice()) {
+                try!(check_growth(v, val.as_slice(), true, opt.as_slice(), &limits));
+                v.push(val.as_slice(), comment);
+            }
+        } else {
+            // a replaced option keeps its place
+            let vals = if reset { vec![] } else { vec![val] };
+            ohash.insert(opt.clone(), InterpString::from_values(vals, comment));
+        }
+        if let Some(v) = ohash.get_mut(opt.as_slice()) {
+            v.origin = origin;
+        }
+    }
+    Ok(Some(opt))
+}
+
+// where an option read in a section goes: sections are made when
+// their header is read, except for the global section, which is made
+// on first use. Options before any section go into the default section
+fn global_or_section<'a>(cp : &'a mut ConfigParser, curr_sect : &String) -> &'a mut Props {
+    if curr_sect.is_empty() {
+        &mut cp.default_sect
+    } else {
+        cp.props_or_create(curr_sect.as_slice())
+    }
+}
+
+// add an indented continuation line (preceded by any blank lines
+// which were held back) onto the value of an option, so long as the
+// value stays within the logical line limit
+fn append_continuation(cp : &mut ConfigParser, text : &str, blanks : usize,
+                       curr_sect : &String, opt : &str) -> Result<(), FetchError> {
+    let max = cp.opts.limits.max_logical_line_length;
+    match global_or_section(cp, curr_sect).get_mut(opt) {
+        Some(v) => {
+            let mut val = v.get_raw();
+            if let Some(m) = max {
+                if val.len() + blanks + 1 + text.len() > m {
+                    return Err(fe_error_detail(FetchErrorKind::LimitExceeded,
+                                               format!("value of {} over {} bytes", opt, m)))
+                }
+            }
+            for _ in 0..blanks {
+                val.push('\n');
+            }
+            val.push('\n');
+            val.push_str(text);
+            v.set_last(val.as_slice());
+        },
+        None => {
+            error!("Option {} vanished from section {} during continuation", opt, curr_sect);
+        }
+    }
+    Ok(())
+}
+
+// split text after its first n lines
+fn split_lines(s : &str, n : usize) -> (String, String) {
+    let mut at = 0;
+    for _ in 0..n {
+        match s[at..].find('\n') {
+            Some(i) => at += i + 1,
+            None => {
+                at = s.len();
+                break;
+            }
+        }
+    }
+    (s[..at].to_string(), s[at..].to_string())
+}
+
+// keep the text of an event for a lossless parser
+fn doc_push(cp : &mut ConfigParser, raw : &str, kind : DocKind) {
+    if ! raw.is_empty() {
+        cp.doc.push(DocLine{ raw : raw.to_string(), kind : kind });
+    }
+}
+
+// add a continuation, with the blank and comment lines before it,
+// onto the text of the option it continues
+fn doc_continue(cp : &mut ConfigParser, raw : &str) {
+    let at = cp.doc.iter().rposition(|d| match d.kind {
+        DocKind::Setting(..) => true,
+        _ => false
+    });
+    match at {
+        Some(i) => {
+            let mut text = String::new();
+            while cp.doc.len() > i + 1 {
+                text.push_str(cp.doc.remove(i + 1).raw.as_slice());
+            }
+            text.push_str(raw);
+            cp.doc[i].raw.push_str(text.as_slice());
+        },
+        None => doc_push(cp, raw, DocKind::Other)
+    }
+}
+
+// the indent, key and delimiter (and the spacing after it) which
+// start an option line, for a new value to follow
+fn option_prefix(raw : &str, option : &str) -> String {
+    let first = raw.lines().next().unwrap_or("");
+    let indent = &first[..first.len() - first.trim_left().len()];
+    match first.find(|c: char| c == ':' || c == '=') {
+        // a plain value replaces an append
+        Some(i) if ! first[..i].trim_right().ends_with("+") => {
+            let after = &first[i+1..];
+            let ws = after.len() - after.trim_left().len();
+            let mut prefix = first[..i+1+ws].to_string();
+            if ws == 0 && first[..i].ends_with(" ") {
+                prefix.push(' ');
+            }
+            prefix
+        },
+        _ => format!("{}{} = ", indent, option)
+    }
+}
+
+// what an include line names
+enum Include {
+    // a file, or a pattern for files
+    Path(String),
+    // a directory of fragments
+    Dir(String)
+}
+
+// match a file name against a pattern with `*` and `?` wildcards.
+// Only the last `*` is ever backtracked to: on a mismatch it takes one
+// more byte of the name, which keeps the match to O(pattern * name)
+fn glob_match(pat : &[u8], name : &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the position after the last `*`, and where in the name it is up to
+    let mut star : Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pat.get(p) {
+            Some(&b'*') => {
+                p += 1;
+                star = Some((p, n));
+            },
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                },
+                None => return false
+            }
+        }
+    }
+    pat[p..].iter().all(|&c| c == b'*')
+}
+
+// read the files of a directory in order of their names: those
+// matching the pattern, or otherwise the `*.conf` and `*.ini`
+// ones. Hidden files are skipped. An unreadable directory, or the
+// first file which cannot be opened, is given back
+fn read_fragments(cp : &mut ConfigParser, dir : &Path, pattern : Option<&[u8]>,
+                  includes : &mut Vec<Path>) -> Result<Option<String>, FetchError> {
+    let mut files : Vec<Path> = match readdir(dir) {
+        Ok(ps) => ps.into_iter().filter(|p| {
+            let name : &[u8] = match p.filename() {
+                Some(n) => n,
+                None => return false
+            };
+            p.is_file() && !name.starts_with(b".") && match pattern {
+                Some(pat) => glob_match(pat, name),
+                None => match p.extension_str() {
+                    Some("conf") | Some("ini") => true,
+                    _ => false
+                }
+            }
+        }).collect(),
+        Err(e) => {
+            error!("Cannot read directory {} for config: {:?}", dir.display(), e);
+            return Ok(Some(format!("{}: {}", dir.display(), e)))
+        }
+    };
+    files.sort_by(|a, b| a.filename().cmp(&b.filename()));
+    let mut unread = None;
+    for f in files.iter() {
+        if let Some(e) = try!(read_path(cp, f, includes)) {
+            if unread.is_none() {
+                unread = Some(format!("{}: {}", f.display(), e));
+            }
+        }
+    }
+    Ok(unread)
+}
+
+// read the file (or files, for a pattern) an include line names,
+// giving what could not be read, if anything
+fn read_include(cp : &mut ConfigParser, inc : Include,
+                includes : &mut Vec<Path>) -> Result<Option<String>, FetchError> {
+    let dir = includes.last().map(|p| p.dir_path());
+    match inc {
+        Include::Dir(d) => {
+            let p = resolve_path(&Path::new(d), dir);
+            read_fragments(cp, &p, None, includes)
+        },
+        Include::Path(f) => {
+            let p = resolve_path(&Path::new(f), dir);
+            let wild = match p.filename() {
+                Some(name) => name.iter().any(|&c| c == b'*' || c == b'?'),
+                None => false
+            };
+            if wild {
+                let pat = p.filename().unwrap().to_vec();
+                read_fragments(cp, &p.dir_path(), Some(pat.as_slice()), includes)
+            } else {
+                read_path(cp, &p, includes).map(|e| e.map(|e| format!("{}: {}", p.display(), e)))
+            }
+        }
+    }
+}
+
+// expand `~user` in a configuration path and make it absolute,
+// relative to the given directory or else the current one. Failures
+// are logged, leaving the path as it was
+fn resolve_path(p : &Path, dir : Option<Path>) -> Path {
+    let exp_p = match expand_homedir(p) {
+        Ok(ep) => ep,
+        Err(e) => {
+            error!("Cannot expand user homedir of {} : {}", p.display(), e);
+            p.clone()
+        }
+    };
+    match dir {
+        Some(ref d) if exp_p.is_relative() => d.join(&exp_p),
+        _ => match abspath(&exp_p) {
+            Ok(ap) => ap,
+            Err(e) => {
+                error!("Cannot make absolute directory of {} : {}", p.display(), e);
+                exp_p.clone()
+            }
+        }
+    }
+}
+
+// read a configuration file, given the stack of files which are
+// (directly or indirectly) including it. A file which cannot be
+// opened is logged and skipped, giving the error
+fn read_path(cp : &mut ConfigParser, p : &Path,
+             includes : &mut Vec<Path>) -> Result<Option<IoError>, FetchError> {
+    if includes.contains(p) {
+        let mut chain : Vec<String> = includes.iter().map(|i| format!("{}", i.display())).collect();
+        chain.push(format!("{}", p.display()));
+        return Err(fe_error_detail(FetchErrorKind::IncludeCircularity, chain.connect(" -> ")))
+    }
+    if includes.len() > cp.opts.max_include_depth {
+        return Err(fe_error_detail(FetchErrorKind::IncludeDepthExceeded,
+                                   format!("{} included from {} beyond depth {}",
+                                           p.display(), includes[includes.len()-1].display(),
+                                           cp.opts.max_include_depth)))
+    }
+    match File::open(p) {
+        Ok(f) => {
+            let mut br = BufferedReader::new(f);
+            let source = format!("{}", p.display());
+            includes.push(p.clone());
+            let enc = cp.opts.encoding;
+            let res = from_reader_helper(cp, &mut br, source.as_slice(), enc, includes);
+            includes.pop();
+            res.map(|_| None)
+        },
+        Err(e) => {
+            error!("Cannot open path {} for config: {:?}", p.display(), e);
+            Ok(Some(e))
+        }
+    }
+}
+
+// how many options there are, in every section and the defaults
+fn option_count(cp : &ConfigParser) -> usize {
+    cp.sections.iter().fold(cp.default_sect.len() + cp.defaults.len(),
+                            |n, (_, opts)| n + opts.len())
+}
+
+// whether setting an option while reading would add one
+fn is_new_option(cp : &ConfigParser, curr_sect : &String, key : &str) -> bool {
+    if curr_sect.is_empty() {
+        cp.opts.global_options == GlobalOptions::Defaults && ! cp.default_sect.contains_key(key)
+    } else {
+        match cp.props(curr_sect.as_slice()) {
+            Some(opts) => ! opts.contains_key(key),
+            None => true
+        }
+    }
+}
+
+// the source name of text read from a string
+static STRING_SOURCE : &'static str = "<string>";
+
+// `source` names the reader for error messages, and `encoding` is
+// how its bytes are decoded
+fn from_reader_helper<T: ContinuationReader>(cp : &mut ConfigParser, r : &mut T,
+                                             source : &str, encoding : Encoding,
+                                             includes : &mut Vec<Path>) -> Result<(), FetchError> {
+    let mut curr_sect = match cp.opts.global_options {
+        GlobalOptions::Section(ref g) => g.clone(),
+        _ => "".to_string()
+    };
+    // for indent continuations: the last option set
+    let mut curr_opt : Option<String> = None;
+    // blank lines seen since, which belong to the value if it continues
+    let mut blanks : usize = 0;
+    // strict mode: where each section and option was first seen
+    let mut seen_sects : HashMap<String, usize> = HashMap::new();
+    let mut seen_opts : HashMap<(String, String), usize> = HashMap::new();
+    // how many options there are, against the limit
+    let mut options = option_count(cp);
+    // lossless mode keeps the text of top level sources, but not that
+    // of the files they include
+    let record = cp.opts.lossless && includes.len() <= 1;
+    if record {
+        // one source must not run into the next
+        if let Some(d) = cp.doc.last_mut() {
+            if ! d.raw.ends_with("\n") {
+                d.raw.push('\n');
+            }
+        }
+    }
+
+    let mut opts = cp.opts.clone();
+    opts.encoding = encoding;
+    let mut events = Events::new(r, &opts);
+    loop {
+        let ev = match events.next() {
+            Some(Ok(ev)) => ev,
+            Some(Err(LineError::TooLong(detail))) => {
+                let fe = fe_error_detail(FetchErrorKind::LimitExceeded,
+                                         format!("{} at line {} of {}",
+                                                 detail, events.line() + 1, source));
+                return Err(rejected(cp, fe, source, events.line() + 1, 0, ""))
+            },
+            Some(Err(LineError::Io(e))) => {
+                error!("Reader error on parser init: {:?}", e);
+                parse_error(cp, ParseErrorKind::ReadError, source, events.line() + 1, 0, "",
+                            Some(format!("{}", e)));
+                break;
+            },
+            None => break
+        };
+        let raw = events.raw().to_string();
+        // where the text of the line starts, for reporting errors
+        let column = raw.len() - raw.trim_left().len() + 1;
+        match ev {
+            Event::Comment{ .. } => {
+                if record {
+                    doc_push(cp, raw.as_slice(), DocKind::Other);
+                }
+            },
+            Event::Blank{ .. } => {
+                if record {
+                    doc_push(cp, raw.as_slice(), DocKind::Other);
+                }
+                blanks += 1;
+            },
+            Event::Continuation{ text, line } => {
+                if let Some(ref opt) = curr_opt {
+                    if let Err(fe) = append_continuation(cp, text.as_slice(), blanks,
+                                                         &curr_sect, opt.as_slice()) {
+                        return Err(rejected(cp, fe, source, line, column, raw.trim()))
+                    }
+                }
+                if record {
+                    doc_continue(cp, raw.as_slice());
+                }
+                blanks = 0;
+            },
+            Event::SectionStart{ name, parent, line } => {
+                let name = name.into_owned();
+                blanks = 0;
+                curr_opt = None;
+                curr_sect = name.clone();
+                if let Some(p) = parent {
+                    cp.parents.insert(name.clone(), p.into_owned());
+                }
+                if record {
+                    doc_push(cp, raw.as_slice(), DocKind::Header(name.clone()));
+                }
+                if cp.opts.strict {
+                    match seen_sects.entry(name.clone()) {
+                        Entry::Occupied(o) => {
+                            let fe = fe_error_detail(
+                                FetchErrorKind::DuplicateSection,
+                                format!("section [{}] in {} at line {} \
+                                         (first seen at line {})",
+                                        name, source, line, o.get()));
+                            return Err(rejected(cp, fe, source, line, column, raw.trim()))
+                        },
+                        Entry::Vacant(v) => { v.insert(line); }
+                    }
+                }
+                if cp.sections.contains_key(name.as_slice()) ||
+                    cp.is_default_section(name.as_slice()) {
+                    continue
+                } // ignore repeat (or default) section
+                if let Some(max) = cp.opts.limits.max_sections {
+                    if cp.sections.len() >= max {
+                        let fe = fe_error_detail(FetchErrorKind::LimitExceeded,
+                                                 format!("section [{}] in {} at line {} \
+                                                          is over the limit of {}",
+                                                         name, source, line, max));
+                        return Err(rejected(cp, fe, source, line, column, raw.trim()))
+                    }
+                }
+                cp.sections.insert(name, OrderedMap::new());
+            },
+            Event::Include{ path, dir, line } => {
+                blanks = 0;
+                curr_opt = None;
+                if record {
+                    doc_push(cp, raw.as_slice(), DocKind::Other);
+                }
+                let path = path.into_owned();
+                let inc = if dir { Include::Dir(path) } else { Include::Path(path) };
+                let before = rejections(cp);
+                match read_include(cp, inc, includes) {
+                    Ok(None) => {},
+                    // a file which cannot be read leaves the
+                    // configuration incomplete, which is this line's
+                    // error, but not one that stops reading
+                    Ok(Some(detail)) => parse_error(cp, ParseErrorKind::ReadError, source,
+                                                    line, column, raw.trim(), Some(detail)),
+                    // errors from within included files are
+                    // recorded already; others are this line's
+                    Err(fe) => return Err(if rejections(cp) > before {
+                        fe
+                    } else {
+                        rejected(cp, fe, source, line, column, raw.trim())
+                    })
+                }
+                options = option_count(cp);
+            },
+            Event::Invalid{ text, line, column } => {
+                blanks = 0;
+                curr_opt = None;
+                if record {
+                    doc_push(cp, raw.as_slice(), DocKind::Other);
+                }
+                warn!("Ignoring malformed line {} of {}: {}", line, source, text);
+                parse_error(cp, ParseErrorKind::InvalidLine, source, line, column,
+                            text.as_slice(), None);
+            },
+            Event::Option{ key, value, comment, append, array, line } => {
+                let (key, value) = (key.into_owned(), value.into_owned());
+                let comment = comment.map(|c| c.into_owned());
+                blanks = 0;
+                // repeats are the point of multi-valued options and
+                // appends; anything else is refused before it is set
+                if cp.opts.strict && ! cp.opts.multi_value && ! append && ! curr_sect.is_empty() {
+                    match seen_opts.entry((curr_sect.clone(), key.clone())) {
+                        Entry::Occupied(o) => {
+                            let fe = fe_error_detail(
+                                FetchErrorKind::DuplicateOption,
+                                format!("option {} of section [{}] in {} at line {} \
+                                         (first seen at line {})",
+                                        key, curr_sect, source, line, o.get()));
+                            return Err(rejected(cp, fe, source, line, column, raw.trim()))
+                        },
+                        Entry::Vacant(v) => { v.insert(line); }
+                    }
+                }
+                if let Some(max) = cp.opts.limits.max_options {
+                    if is_new_option(cp, &curr_sect, key.as_slice()) {
+                        if options >= max {
+                            let fe = fe_error_detail(FetchErrorKind::LimitExceeded,
+                                                     format!("option {} in {} at line {} \
+                                                              is over the limit of {}",
+                                                             key, source, line, max));
+                            return Err(rejected(cp, fe, source, line, column, raw.trim()))
+                        }
+                        options += 1;
+                    }
+                }
+                curr_opt = match set_option(cp, &curr_sect, key, value, comment, append, array,
+                                            source, line) {
+                    Ok(o) => o,
+                    Err(fe) => return Err(rejected(cp, fe, source, line, column, raw.trim()))
+                };
+                if record {
+                    // options kept from outside any section are the
+                    // default section's, to be edited through it
+                    let kind = match (curr_opt.as_ref(), cp.opts.default_section.as_ref()) {
+                        (Some(o), Some(d)) if curr_sect.is_empty() =>
+                            DocKind::Setting(d.clone(), o.clone()),
+                        (Some(o), _) if ! curr_sect.is_empty() =>
+                            DocKind::Setting(curr_sect.clone(), o.clone()),
+                        _ => DocKind::Other
+                    };
+                    doc_push(cp, raw.as_slice(), kind);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// the boolean a value stands for. Note that empty string counts as
+// true, ie, so we can have things like getboolean("foo", "skip_init")
+// return true if we just have "skip_init" in config string, not
+// necessarily "skip_init = true"
+fn bool_literal(v : &str) -> Option<bool> {
+    let trues = vec!["true","yes","on","1",""];
+    let falses = vec!["false", "no", "off", "0"];
+
+    let lv = v.to_string().into_ascii_lowercase();
+    for &t in trues.iter() {
+        if t == lv {
+            return Some(true)
+        }
+    }
+    for &f in falses.iter() {
+        if f == lv {
+            return Some(false)
+        }
+    }
+    None
+}
+
+// the number a value stands for, where empty string counts as `one`
+fn number_literal<T: FromStr>(v : &str, one : T) -> Option<T> {
+    if v == "" {
+        Some(one)
+    } else {
+        FromStr::from_str(v).ok()
+    }
+}
+
+fn abspath(p: &Path) -> IoResult<Path> {
+    match p.is_absolute() {
+        true => Ok(p.clone()),
+        _ => match env::current_dir() {
+            Ok(mut cwd) => {cwd.push(p); Ok(cwd)},
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl ConfigParser {
+    ///
+    /// Creates an empty ConfigParser with default key,value pairs
     ///
     /// # Example
     ///
     /// ```
     /// use conparse::conparse::ConfigParser;
-    /// 
-    /// let mut cp = ConfigParser::from_str("[foo]\n\
-    ///    bar = quux\n\
-    ///    \n\
-    ///    [wibble]\n\
-    ///    bar2 = quux2\n", &[]);
-    /// assert!(cp.has_section("foo"));
-    /// cp.remove_section("foo");
-    /// assert!(! cp.has_section("foo"));
-    /// assert!(cp.get("foo", "bar").is_err()); // NoSuchSection
+    ///
+    /// let mut cp = ConfigParser::new(&[("host","localhost"), ("port","22"), ("protocol","tcp")]);
     /// ```
-    pub fn remove_section(&mut self, s : &str) -> Result<(), FetchError> {
-        match self.sections.remove(s) {
-            Some(_) => Ok(()),
-            None => Err(fe_error(FetchErrorKind::NoSuchSection))
+    ///
+    pub fn new(kvdefaults : &[(&str, &str)]) -> ConfigParser {
+        ConfigParser::with_options(kvdefaults, ParserOptions::new())
+    }
+
+    ///
+    /// Creates an empty ConfigParser with default key,value pairs,
+    /// which will read and write according to the supplied
+    /// `ParserOptions`. Use the `read_*` methods to load
+    /// configuration text into it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::{ConfigParser,ParserOptions};
+    ///
+    /// let mut opts = ParserOptions::new();
+    /// opts.inline_comment_prefixes = vec![";".to_string()];
+    /// let mut cp = ConfigParser::with_options(&[], opts);
+    /// assert!(cp.read_str("[server]\nport = 8080 ; legacy port\n").is_ok());
+    /// assert_eq!(cp.get("server", "port").unwrap(), "8080");
+    /// ```
+    ///
+    pub fn with_options(kvdefaults : &[(&str, &str)], opts : ParserOptions) -> ConfigParser {
+        let mut df = HashMap::new();
+        for &(k,v) in kvdefaults.iter() {
+            df.insert(k.to_string(), v.to_string());
+        }
+        let sects : OrderedMap<Props> = OrderedMap::new();
+        ConfigParser { defaults: df, sections : sects, opts : opts,
+                       default_sect : OrderedMap::new(), parents : HashMap::new(),
+                       parse_errors : vec![], doc : vec![] }
+    }
+
+    // the parser, or the problems found reading into it
+    fn checked(mut self, res : Result<(), FetchError>) -> Result<ConfigParser, Vec<ParseError>> {
+        if let Err(fe) = res {
+            if rejections(&self) == 0 {
+                rejected(&mut self, fe, "", 0, 0, "");
+            }
+        }
+        if self.parse_errors.is_empty() {
+            Ok(self)
+        } else {
+            Err(self.parse_errors)
         }
     }
 
     ///
-    /// Returns a boolean as to whether a given section exists or not
+    /// As `from_readers`, but failing with every problem found (see
+    /// `try_from_str`) rather than ignoring what cannot be read
+    ///
+    pub fn try_from_readers<T: ContinuationReader>(rs : &mut[ &mut T ],
+                                                   kvdefaults : &[(&str, &str)])
+                                                   -> Result<ConfigParser, Vec<ParseError>> {
+        let mut cp = ConfigParser::new(kvdefaults);
+        let res = cp.read_readers(rs);
+        cp.checked(res)
+    }
+
+    ///
+    /// As `from_str`, but failing with a `ParseError` for every line
+    /// which is malformed, or for a source which cannot be read,
+    /// rather than ignoring them
     ///
     /// # Example
+    ///
     /// ```
-    /// use conparse::conparse::ConfigParser;
+    /// use conparse::conparse::{ConfigParser,ParseErrorKind};
     ///
-    /// let cp = ConfigParser::from_str("[foo]\n[bar]\n", &[]);
-    /// assert!(cp.has_section("foo"));
-    /// assert!(! cp.has_section("quux"));
+    /// match ConfigParser::try_from_str("[app]\nport = 80\n[log\n", &[]) {
+    ///     Ok(_) => panic!("config should be rejected"),
+    ///     Err(errs) => {
+    ///         assert_eq!(errs.len(), 1);
+    ///         assert_eq!(errs[0].kind(), ParseErrorKind::InvalidLine);
+    ///         assert_eq!(errs[0].line(), 3);
+    ///         assert_eq!(errs[0].text(), "[log");
+    ///     }
+    /// }
     /// ```
-    pub fn has_section(&self, s : &str) -> bool {
-        self.sections.contains_key(s)
+    ///
+    pub fn try_from_str(s : &str, kvdefaults : &[(&str, &str)])
+                        -> Result<ConfigParser, Vec<ParseError>> {
+        let mut cp = ConfigParser::new(kvdefaults);
+        let res = cp.read_str(s);
+        cp.checked(res)
+    }
+
+    ///
+    /// As `from_strs`, but failing with every problem found (see
+    /// `try_from_str`)
+    ///
+    pub fn try_from_strs(ss : &[ &str ], kvdefaults : &[(&str, &str)])
+                         -> Result<ConfigParser, Vec<ParseError>> {
+        let mut cp = ConfigParser::new(kvdefaults);
+        let res = cp.read_strs(ss);
+        cp.checked(res)
+    }
+
+    ///
+    /// As `from_files`, but failing with every problem found (see
+    /// `try_from_str`). Files which cannot be opened are still
+    /// skipped.
+    ///
+    pub fn try_from_files(ss : &[ &str ], kvdefaults : &[(&str, &str)])
+                          -> Result<ConfigParser, Vec<ParseError>> {
+        let mut cp = ConfigParser::new(kvdefaults);
+        let res = cp.read_files(ss);
+        cp.checked(res)
+    }
+
+    ///
+    /// As `from_file`, but failing with every problem found (see
+    /// `try_from_str`)
+    ///
+    pub fn try_from_file(s : &str, kvdefaults : &[(&str, &str)])
+                         -> Result<ConfigParser, Vec<ParseError>> {
+        ConfigParser::try_from_files(&[ s ], kvdefaults)
+    }
+
+    ///
+    /// As `from_dir`, but failing with every problem found (see
+    /// `try_from_str`)
+    ///
+    pub fn try_from_dir(d : &str, kvdefaults : &[(&str, &str)])
+                        -> Result<ConfigParser, Vec<ParseError>> {
+        let mut cp = ConfigParser::new(kvdefaults);
+        let res = cp.read_dir(d);
+        cp.checked(res)
+    }
+
+    ///
+    /// The problems found in all the sources read so far: malformed
+    /// lines, sources which could not be read, and the errors which
+    /// stopped reading
+    ///
+    pub fn parse_errors(&self) -> Vec<ParseError> {
+        self.parse_errors.clone()
+    }
+
+    //
+    // Strongly suspect (ie, know) that there's way too much
+    // mutability in here. Most of the time I just want to pass
+    // a slice of readers into a function. The readers will be
+    // mutated, but the slice itself should not be, so passing as
+    // &mut[ &mut T ] seems wrong.
+    //
+
+    ///
+    /// Create a new ConfigParser from a slice of `Reader`s which can
+    /// implement the `ContinuationReader` trait. In theory you could
+    /// pass a slice of ContinuationReader trait objects, but I need
+    /// to find out how to make them implement the Sized trait
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #![feature(path)]
+    /// #![feature(io)]
+    /// use conparse::conparse::{ConfigParser};
+    /// use std::old_io::{BufferedReader,File};
+    /// use std::old_path::Path;
+    ///
+    /// fn open_files(fs : &[ &str ]) -> Vec<BufferedReader<File>> {
+    ///     fs.iter().filter_map(|&p| match File::open(&Path::new(p)) {
+    ///         Ok(f) => Some(BufferedReader::new(f)),
+    ///         Err(_) => None
+    ///     }).collect()
+    /// }
+    ///
+    /// let mut of = open_files(&["/etc/myapp/myconfig.txt",
+    ///                           "/usr/local/etc/myapp/extra-config.txt"]);
+    /// let mut mof : Vec<&mut BufferedReader<File>> = of.iter_mut().collect();
+    /// let cp = ConfigParser::from_readers(mof.as_mut_slice(), &[("host", "localhost")]);
+    /// ```
+    ///
+    pub fn from_readers<T: ContinuationReader>(rs : &mut[ &mut T ],
+                                               kvdefaults : &[(&str, &str)]) -> ConfigParser {
+        let mut cp = ConfigParser::new(kvdefaults);
+        if let Err(e) = cp.read_readers(rs) {
+            error!("Error reading configuration: {}", e);
+        }
+        cp
+    }
+
+    ///
+    /// Create a new ConfigParser from a string specification
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_str(
+    ///          "[myapp]\n log_level = DEBUG", &[("log_level","WARN")]);
+    /// ```
+    ///
+    pub fn from_str(s: &str, kvdefaults : &[(&str, &str)]) -> ConfigParser {
+        let mut cp = ConfigParser::new(kvdefaults);
+        if let Err(e) = cp.read_str(s) {
+            error!("Error reading configuration: {}", e);
+        }
+        cp
+    }
+
+    ///
+    /// Create a new ConfigParser from a list of string contents
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_strs(&["[myapp]\n log_level = DEBUG",
+    ///                 "[global]\ngreeting = Hello\n"], &[("log_level","INFO")]);
+    /// ```
+    ///
+    pub fn from_strs(ss: &[ &str ], kvdefaults : &[(&str, &str)]) -> ConfigParser {
+        let mut cp = ConfigParser::new(kvdefaults);
+        if let Err(e) = cp.read_strs(ss) {
+            error!("Error reading configuration: {}", e);
+        }
+        cp
+    }
+
+
+    ///
+    /// Create a new ConfigParser from reading a list of files
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_files(&["/etc/myapp/config.txt",
+    ///                       "~/.myapp.cfg"], &[("log_level","INFO")]);
+    /// ```
+    ///
+    pub fn from_files(ss : &[ &str ], kvdefaults : &[(&str, &str)]) -> ConfigParser {
+        let mut cp = ConfigParser::new(kvdefaults);
+        if let Err(e) = cp.read_files(ss) {
+            error!("Error reading configuration: {}", e);
+        }
+        cp
+    }
+
+    ///
+    /// Create a new ConfigParser from reading a file
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_file("/etc/myapp/config.txt", &[("log_level","INFO")]);
+    /// ```
+    ///
+    pub fn from_file(s : &str, kvdefaults : &[(&str, &str)]) -> ConfigParser {
+        ConfigParser::from_files(&[ s ], kvdefaults)
+    }
+
+    ///
+    /// Create a new ConfigParser from the `*.conf` and `*.ini` files
+    /// of a directory, read in order of their names (see `read_dir`)
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_dir("/etc/myapp/conf.d", &[("log_level","INFO")]);
+    /// ```
+    ///
+    pub fn from_dir(d : &str, kvdefaults : &[(&str, &str)]) -> ConfigParser {
+        let mut cp = ConfigParser::new(kvdefaults);
+        if let Err(e) = cp.read_dir(d) {
+            error!("Error reading configuration: {}", e);
+        }
+        cp
+    }
+
+    ///
+    /// Read configuration from a slice of `ContinuationReader`s into
+    /// an existing parser. Values read replace any which are already
+    /// present, and later readers override earlier ones. Reading
+    /// stops at the first reader with an error (which can only happen
+    /// with the `strict` option).
+    ///
+    pub fn read_readers<T: ContinuationReader>(&mut self,
+                                               rs : &mut[ &mut T ]) -> Result<(), FetchError> {
+        for (i, r) in rs.iter_mut().enumerate() {
+            let enc = self.opts.encoding;
+            let source = format!("<reader {}>", i + 1);
+            try!(from_reader_helper(self, *r, source.as_slice(), enc, &mut vec![]))
+        }
+        Ok(())
+    }
+
+    ///
+    /// Read configuration from a string into an existing parser
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let mut cp = ConfigParser::new(&[]);
+    /// assert!(cp.read_str("[myapp]\n log_level = DEBUG").is_ok());
+    /// assert_eq!(cp.get("myapp", "log_level").unwrap(), "DEBUG");
+    /// ```
+    ///
+    pub fn read_str(&mut self, s: &str) -> Result<(), FetchError> {
+        let mut v = MemReader::new(s.as_bytes().to_vec());
+        // the text is a string already, whatever the options say
+        from_reader_helper(self, &mut v, STRING_SOURCE, Encoding::Utf8Strict, &mut vec![])
+    }
+
+    ///
+    /// Read configuration from a list of strings into an existing
+    /// parser
+    ///
+    pub fn read_strs(&mut self, ss: &[ &str ]) -> Result<(), FetchError> {
+        for s in ss.iter() {
+            try!(self.read_str(*s))
+        }
+        Ok(())
+    }
+
+    ///
+    /// Read configuration from a list of files into an existing
+    /// parser. Paths of the form `~user/file` are expanded, and
+    /// files which cannot be opened are logged and skipped.
+    ///
+    pub fn read_files(&mut self, ss : &[ &str ]) -> Result<(), FetchError> {
+        self.read(ss).map(|_| ())
+    }
+
+    ///
+    /// Read configuration from a list of files into an existing
+    /// parser, as Python's `ConfigParser.read()` does, reporting
+    /// which of the files were loaded and which could not be opened
+    /// (files they include are not listed). As with `read_files`,
+    /// files which cannot be opened are skipped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let mut cp = ConfigParser::new(&[]);
+    /// let report = cp.read(&["/etc/myapp/config.txt", "~/.myapprc"]).unwrap();
+    /// for &(ref p, ref e) in report.failed.iter() {
+    ///     println!("Skipped {}: {}", p.display(), e);
+    /// }
+    /// ```
+    ///
+    pub fn read(&mut self, ss : &[ &str ]) -> Result<ReadReport, FetchError> {
+        self.read_reporting(ss, false)
+    }
+
+    ///
+    /// As `read`, but failing with a `FetchErrorKind::UnreadableFile`
+    /// error at the first file which cannot be opened. Files before
+    /// it have been read by then.
+    ///
+    pub fn read_required(&mut self, ss : &[ &str ]) -> Result<ReadReport, FetchError> {
+        self.read_reporting(ss, true)
+    }
+
+    fn read_reporting(&mut self, ss : &[ &str ], required : bool) -> Result<ReadReport, FetchError> {
+        let mut report = ReadReport{ loaded : vec![], failed : vec![] };
+        for s in ss.iter() {
+            let p = resolve_path(&Path::new(*s), None);
+            match try!(read_path(self, &p, &mut vec![])) {
+                None => report.loaded.push(p),
+                Some(e) => {
+                    if required {
+                        let source = format!("{}", p.display());
+                        let fe = fe_error_detail(FetchErrorKind::UnreadableFile,
+                                                 format!("{}: {}", source, e));
+                        return Err(rejected(self, fe, source.as_slice(), 0, 0, ""))
+                    }
+                    report.failed.push((p, e))
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    ///
+    /// Read configuration from a file into an existing parser
+    ///
+    pub fn read_file(&mut self, s : &str) -> Result<(), FetchError> {
+        self.read_files(&[ s ])
+    }
+
+    ///
+    /// Read the `*.conf` and `*.ini` files of a directory into an
+    /// existing parser, in order of their names, so that later
+    /// fragments override earlier ones (as with `/etc/sysctl.d`).
+    /// Hidden files are skipped. The directory name is expanded as
+    /// `read_files` does. A directory (or a file in it) which cannot
+    /// be read is skipped, and recorded as a `ReadError` (see
+    /// `parse_errors`), so that `try_from_dir` fails.
+    ///
+    pub fn read_dir(&mut self, d : &str) -> Result<(), FetchError> {
+        let p = resolve_path(&Path::new(d), None);
+        if let Some(detail) = try!(read_fragments(self, &p, None, &mut vec![])) {
+            let source = format!("{}", p.display());
+            parse_error(self, ParseErrorKind::ReadError, source.as_slice(), 0, 0, "",
+                        Some(detail));
+        }
+        Ok(())
+    }
+
+    // the text of a value as it should appear in written output.
+    // With `indent_continuation`, values with embedded newlines are
+    // written as indented continuation lines (see `writable`)
+    fn format_value(&self, v: &str) -> String {
+        if self.opts.quoted_values && self.needs_quoting(v) {
+            return quote_value(v)
+        }
+        let esc = if self.opts.inline_comment_prefixes.is_empty() {
+            v.to_string()
+        } else {
+            escape_inline_comment(v, self.opts.inline_comment_prefixes.as_slice())
+        };
+        if ! self.opts.indent_continuation {
+            return esc
+        }
+        let mut res = String::new();
+        for (i, l) in esc.split('\n').enumerate() {
+            if i > 0 {
+                res.push('\n');
+                if ! l.is_empty() {
+                    res.push_str("    ");
+                }
+            }
+            res.push_str(l);
+        }
+        res
+    }
+
+    // true if format_value writes the value so that it reads back the
+    // same. Unless quoted, a value with newlines needs indented
+    // continuation lines, and any of them which starts as a comment
+    // would be skipped, as would space around them or a final newline
+    fn writable(&self, v: &str) -> bool {
+        if ! v.contains("\n") || (self.opts.quoted_values && self.needs_quoting(v)) {
+            return true
+        }
+        self.opts.indent_continuation && ! v.ends_with("\n") &&
+            v.split('\n').skip(1).all(|l| {
+                l.trim() == l && ! l.starts_with("#") && ! l.starts_with(";")
+            })
+    }
+
+    // an error naming the first option with a value which would not
+    // read back as it is, if there is one
+    fn check_writable(&self) -> IoResult<()> {
+        let default_name = match self.opts.default_section {
+            Some(ref d) => d.as_slice(),
+            None => ""
+        };
+        let all = Some((default_name, &self.default_sect)).into_iter().chain(
+            self.sections.iter().map(|(s, opts)| (s.as_slice(), opts)));
+        for (s, opts) in all {
+            for (k, v) in opts.iter() {
+                if ! v.values.iter().all(|rv| self.writable(rv.as_slice())) {
+                    return Err(IoError { kind: IoErrorKind::InvalidInput,
+                                         desc: "Value cannot be written so as to read back",
+                                         detail: Some(format!("option {} of section [{}]",
+                                                              k, s)) })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // true if the value would not read back unchanged unless quoted
+    fn needs_quoting(&self, v: &str) -> bool {
+        v.starts_with("\"") || v.ends_with("\\") ||
+            v.trim() != v ||
+            v.chars().any(|c| c.is_control()) ||
+            self.opts.inline_comment_prefixes.iter().any(
+                |p| ! p.is_empty() && v.contains(p.as_slice()))
+    }
+
+    // write the options of a section, in order
+    fn write_options(&self, w: &mut Writer, o_raw: Iter<InterpString>) -> IoResult<()> {
+        let mut o : Vec<(&String,&InterpString)> = o_raw.collect();
+        if self.opts.sorted_output {
+            o.sort_by(|&(k1,_), &(k2,_)| k1.cmp(k2));
+        }
+
+        for &(k,v) in o.iter() {
+            // multi-valued options are written as repeated lines,
+            // with any comment on the last one. A cleared one
+            // is written as an empty assignment
+            let mut vals = v.values();
+            if vals.is_empty() {
+                vals.push(String::new());
+            }
+            for (i, rv) in vals.iter().enumerate() {
+                let val = self.format_value(rv.as_slice());
+                let res = match v.comment() {
+                    Some(ref c) if i + 1 == vals.len() =>
+                        write!(w, "{} : {} {}\n", k, val, c),
+                    _ => write!(w, "{} : {}\n", k, val)
+                };
+                match res {
+                    Ok(_) => {},
+                    Err(_) =>
+                        return Err(
+                            IoError {
+                                kind: IoErrorKind::ResourceUnavailable,
+                                desc: "Internal ConfigParser write error",
+                                detail:
+                                Some("Internal ConfigParser error: \
+                                      option not found during writing"
+                                     .to_string())})
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_writer(&self, w: &mut Writer) -> IoResult<()> {
+        try!(self.check_writable());
+        if self.opts.lossless {
+            // the text as read, with any edits already made to it
+            // (and any byte order mark it began with), as UTF-8
+            for d in self.doc.iter() {
+                try!(w.write_str(d.raw.as_slice()));
+            }
+            return Ok(())
+        }
+        let mut ss : Vec<&String> = self.sections().collect();
+        if self.opts.sorted_output {
+            ss.sort();
+        }
+        // the default section (if it has anything in it) goes first.
+        // Without a name, its options can only have come from outside
+        // any section, and are written back there
+        match self.opts.default_section {
+            Some(ref d) => if ! self.default_sect.is_empty() {
+                ss.insert(0, d);
+            },
+            None => if ! self.default_sect.is_empty() {
+                try!(self.write_options(w, self.default_sect.iter()));
+                try!(w.write_str("\n"));
+            }
+        }
+
+        for s in ss.iter() {
+            let header = match self.parents.get(*s) {
+                Some(p) => write!(w, "[{} : {}]\n", s, p),
+                None => write!(w, "[{}]\n", s)
+            };
+            match header {
+                Ok(_) => {} // continue
+                Err(_) =>
+                    return Err(
+                        IoError { 
+                            kind: IoErrorKind::ResourceUnavailable,
+                            desc: "Internal ConfigParser write error",
+                            detail:
+                            Some("Internal ConfigParser error: \
+                                  section not found during writing section"
+                                 .to_string())})
+            }
+            match self.options(s.as_slice()) {
+                Ok(o_raw) => try!(self.write_options(w, o_raw)),
+                Err(_) =>
+                    return Err(IoError { kind: IoErrorKind::ResourceUnavailable,
+                                         desc: "Internal ConfigParser write error",
+                                         detail:
+                                         Some("Internal ConfigParser error: \
+                                               unable to find options during writing"
+                                              .to_string())})
+            }
+            // blank line at end of each section
+            match write!(w, "\n") {
+                Ok(_) => {} // continue
+                Err(_) =>
+                    return Err(IoError { kind: IoErrorKind::ResourceUnavailable,
+                                         desc: "Internal ConfigParser write error",
+                                         detail: Some("Internal ConfigParser \
+                                                       error during writing"
+                                                      .to_string())})
+            }            
+        }
+        Ok(()) // return success unit val
+    }
+
+    // convenience method for spitting to file
+    pub fn to_file(&self, fpath: &str) -> IoResult<()> {
+        let p = Path::new(fpath);
+        match File::open_mode(&p, Open, ReadWrite) {
+            Ok(mut f) => self.to_writer(&mut f),
+            Err(e) => {
+                error!("Unable to write to file {} : {}", fpath, e);
+                Err(e)
+            }
+        }
+    }
+
+    // convenience method for spitting to a string
+    pub fn to_string(&self) -> IoResult<String> {
+        let mut w = MemWriter::new();
+        match self.to_writer(&mut w) {
+            Ok(_) => {
+                let s = String::from_utf8(w.into_inner());
+                match s {
+                    Ok(ret) => Ok(ret),
+                    Err(_) =>
+                        return Err(
+                            IoError {
+                                kind: IoErrorKind::ResourceUnavailable,
+                                desc: "Internal ConfigParser write error",
+                                detail: Some("Internal ConfigParser error during UTF-8 translation"
+                                             .to_string())})
+                }
+            },
+            Err(e) => {
+                error!("Unable to write to string : {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    fn is_default_section(&self, s: &str) -> bool {
+        self.opts.is_default_section(s)
+    }
+
+    // the options of a section (which may be the default section)
+    fn props(&self, section: &str) -> Option<&Props> {
+        if self.is_default_section(section) {
+            Some(&self.default_sect)
+        } else {
+            self.sections.get(section)
+        }
+    }
+
+    fn props_mut(&mut self, section: &str) -> Option<&mut Props> {
+        if self.is_default_section(section) {
+            Some(&mut self.default_sect)
+        } else {
+            self.sections.get_mut(section)
+        }
+    }
+
+    // as props_mut, but making the section if it does not exist
+    fn props_or_create(&mut self, section: &str) -> &mut Props {
+        if self.is_default_section(section) {
+            return &mut self.default_sect
+        }
+        self.sections.get_or_insert_with(section, || OrderedMap::new())
+    }
+
+    ///
+    /// Returns the key under which a section with a name and an
+    /// optional git-config style subsection (as in `[remote
+    /// "origin"]`) is stored. This is the text between the brackets
+    /// of the section header, with the subsection quoted and any
+    /// `"` or `\` characters in it escaped. All of the methods which
+    /// take a section name accept these keys.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_str("[remote \"origin\"]\nurl = git://host/repo\n", &[]);
+    /// let key = ConfigParser::section_key("remote", Some("origin"));
+    /// assert_eq!(key, "remote \"origin\"");
+    /// assert_eq!(cp.get(key.as_slice(), "url").unwrap(), "git://host/repo");
+    /// assert_eq!(ConfigParser::section_key("core", None), "core");
+    /// ```
+    ///
+    pub fn section_key(name: &str, subsection: Option<&str>) -> String {
+        match subsection {
+            None => name.to_string(),
+            Some(sub) => {
+                let mut res = format!("{} \"", name);
+                for c in sub.chars() {
+                    if c == '"' || c == '\\' {
+                        res.push('\\');
+                    }
+                    res.push(c);
+                }
+                res.push('"');
+                res
+            }
+        }
+    }
+
+    ///
+    /// Splits a section key back into its name and subsection (if
+    /// any); the reverse of `section_key`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let (name, sub) = ConfigParser::split_section_key("branch \"main\"");
+    /// assert_eq!(name, "branch");
+    /// assert_eq!(sub, Some("main".to_string()));
+    /// ```
+    ///
+    pub fn split_section_key(key: &str) -> (String, Option<String>) {
+        let k = key.trim();
+        let (name, rest) = match k.find(|c: char| c.is_whitespace()) {
+            Some(i) => (&k[..i], k[i..].trim_left()),
+            None => return (k.to_string(), None)
+        };
+        if rest.len() < 2 || ! rest.starts_with("\"") || ! rest.ends_with("\"") {
+            return (k.to_string(), None)
+        }
+        let mut sub = String::new();
+        let mut escaped = false;
+        for c in rest[1..rest.len()-1].chars() {
+            if escaped {
+                sub.push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                // unescaped quote, so this isn't a subsection after all
+                return (k.to_string(), None)
+            } else {
+                sub.push(c);
+            }
+        }
+        if escaped {
+            // the closing quote was escaped
+            return (k.to_string(), None)
+        }
+        (name.to_string(), Some(sub))
+    }
+
+    ///
+    /// Adds an (empty) section to a mutable `ConfigParser` object.
+    /// If the section already exists, a
+    /// `FetchError::DuplicateSection` error is returned, otherwise
+    /// `()` is returned as an `Ok` value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let mut cp = ConfigParser::new(&[]);
+    /// assert!(cp.add_section("foo").is_ok());
+    /// assert!(cp.add_section("foo").is_err()); // duplicating section
+    /// ```
+    pub fn add_section(&mut self, s : &str) -> Result<(), FetchError> {
+        if self.is_default_section(s) {
+            // always exists
+            return Err(fe_error(FetchErrorKind::DuplicateSection))
+        }
+        if self.sections.contains_key(s) {
+            return Err(fe_error(FetchErrorKind::DuplicateSection))
+        }
+        self.sections.insert(s.to_string(), OrderedMap::new());
+        if self.opts.lossless {
+            self.doc_add_section(s);
+        }
+        Ok(())
+    }
+
+    /// Zaps a section from a `ConfigParser`. If the section does not
+    /// exist, a `FetchError::NoSuchSection` is returned as error.
+    /// Otherwise `()` is returned as an `Ok` value. Note that this
+    /// deletes all options contained within the section.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    /// 
+    /// let mut cp = ConfigParser::from_str("[foo]\n\
+    ///    bar = quux\n\
+    ///    \n\
+    ///    [wibble]\n\
+    ///    bar2 = quux2\n", &[]);
+    /// assert!(cp.has_section("foo"));
+    /// cp.remove_section("foo");
+    /// assert!(! cp.has_section("foo"));
+    /// assert!(cp.get("foo", "bar").is_err()); // NoSuchSection
+    /// ```
+    pub fn remove_section(&mut self, s : &str) -> Result<(), FetchError> {
+        self.parents.remove(s);
+        match self.sections.remove(s) {
+            Some(_) => {
+                // comments within the section are left alone
+                self.doc.retain(|d| ! d.in_section(s));
+                Ok(())
+            },
+            None => Err(fe_error(FetchErrorKind::NoSuchSection))
+        }
+    }
+
+    ///
+    /// Returns a boolean as to whether a given section exists or not
+    ///
+    /// # Example
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_str("[foo]\n[bar]\n", &[]);
+    /// assert!(cp.has_section("foo"));
+    /// assert!(! cp.has_section("quux"));
+    /// ```
+    pub fn has_section(&self, s : &str) -> bool {
+        self.sections.contains_key(s)
+    }
+
+    ///
+    /// sets an option to a given value, inside a given section
+    /// if the section does not exist, it will be created. If the
+    /// option within the section already exists, it will be
+    /// overwritten. Returns no result.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let mut cp = ConfigParser::new(&[]);
+    /// cp.set("mysection", "myoption", "myvalue" );
+    /// ```
+    ///
+    pub fn set(&mut self, section: &str, option: &str, value: &str) -> () {
+        self.props_or_create(section).insert(option.to_string(), InterpString::new(value));
+        if self.opts.lossless {
+            self.doc_set(section, option, value);
+        }
+    }
+
+    // in lossless mode, put the new value of an option in place of
+    // the first of its lines (keeping the indent, key and delimiter),
+    // dropping any others, or add a line at the end of its section
+    fn doc_set(&mut self, section: &str, option: &str, value: &str) {
+        let val = self.format_value(value);
+        match self.doc.iter().position(|d| d.is_option(section, option)) {
+            Some(i) => {
+                let raw = format!("{}{}\n", option_prefix(self.doc[i].raw.as_slice(), option), val);
+                self.doc[i].raw = raw;
+                let mut j = i + 1;
+                while j < self.doc.len() {
+                    if self.doc[j].is_option(section, option) {
+                        self.doc.remove(j);
+                    } else {
+                        j += 1;
+                    }
+                }
+            },
+            None => {
+                let at = match self.doc.iter().rposition(|d| d.in_section(section)) {
+                    Some(i) => i + 1,
+                    None => {
+                        self.doc_add_section(section);
+                        self.doc.len()
+                    }
+                };
+                self.doc.insert(at, DocLine{ raw : format!("{} = {}\n", option, val),
+                                             kind : DocKind::Setting(section.to_string(),
+                                                                     option.to_string()) });
+            }
+        }
+    }
+
+    // in lossless mode, add a section header at the end, after a
+    // blank line
+    fn doc_add_section(&mut self, section: &str) {
+        let mut sep = String::new();
+        if let Some(d) = self.doc.last() {
+            if ! d.raw.ends_with("\n") {
+                sep.push('\n');
+            }
+            if ! d.raw.trim().is_empty() {
+                sep.push('\n');
+            }
+        }
+        if ! sep.is_empty() {
+            self.doc.push(DocLine{ raw : sep, kind : DocKind::Other });
+        }
+        let header = match self.parents.get(section) {
+            Some(p) => format!("[{} : {}]\n", section, p),
+            None => format!("[{}]\n", section)
+        };
+        self.doc.push(DocLine{ raw : header, kind : DocKind::Header(section.to_string()) });
+    }
+
+    ///
+    /// Deletes an option from a given section
+    /// If the option does not exist, `FetchError::NoSuchOption` is returned as error
+    /// If the section does not exist, `FetchError::NoSuchSection` is
+    /// returned as error
+    /// On successful completion, `()` is returned as `Ok` value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let mut cp = ConfigParser::new(&[]);
+    ///
+    /// cp.set("foosection", "baroption", "quuxval");
+    /// assert!(cp.get("foosection", "baroption").is_ok());
+    /// cp.remove_option("foosection", "baroption");
+    /// assert!(cp.get("foosection", "baroption").is_err());
+    /// ```
+    pub fn remove_option(&mut self, section : &str, option: &str) -> Result<(),FetchError> {
+        match self.props_mut(section) {
+            Some(opts) => {
+                match opts.remove(option) {
+                    Some(_) => {},
+                    None => return Err(fe_error(FetchErrorKind::NoSuchOption))
+                }
+            },
+            None => return Err(fe_error(FetchErrorKind::NoSuchSection))
+        }
+        self.doc.retain(|d| ! d.is_option(section, option));
+        Ok(())
+    }
+
+    // the default section is searched before the defaults supplied
+    // at construction time
+    fn get_default(&self, option: &str, fe: FetchErrorKind) -> Result<String, FetchError> {
+        match self.default_sect.get(option) {
+            Some(v) => return Ok(v.get_raw()),
+            None => {}
+        }
+        match self.defaults.get(option) {
+            Some(v) => Ok(v.clone()),
+            None => Err(fe_error(fe))
+        }
+    }
+
+    // as get_default, but values from the default section are
+    // interpolated in the context of the section asked for
+    fn get_default_interp(&self, section: &str, option: &str, fe: FetchErrorKind,
+                          expanded : &mut HashSet<String>,
+                          resolved : &mut HashMap<String, String>) -> Result<String, FetchError> {
+        match self.default_sect.get(option) {
+            Some(v) => v.get_resolved(section, option, self, expanded, resolved),
+            None => self.get_default(option, fe)
+        }
+    }
+
+    ///
+    /// Fetches a given option from a given section, and returns the
+    /// value of the option *without* interpolation. If the section
+    /// does not exist `FetchError::NoSuchSection` is returned as
+    /// error; if the option does not exist,
+    /// `FetchError::NoSuchOption` is returned as error. Otherwise the
+    /// raw option is returned as a `String`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp =
+    /// ConfigParser::from_str("[foo]\n\
+    ///     bar=quux\n\
+    ///     bar2=%(bar)s is fun\n", &[]);
+    ///
+    /// let mut b2 = cp.get("foo", "bar2");
+    /// assert!(b2.is_ok() && b2.unwrap().as_slice() == "quux is fun"); // interpolate
+    /// let mut b2 = cp.get_raw("foo", "bar2");
+    /// assert!(b2.is_ok() && b2.unwrap().as_slice() == "%(bar)s is fun");
+    /// // no interpolation with get_raw
+    /// ```
+    pub fn get_raw(&self, section: &str, option: &str) -> Result<String, FetchError> {
+        match self.sections.get(section) {
+            Some(opts) => match opts.get(option) {
+                Some(v) => Ok(v.get_raw()),
+                None => match try!(self.inherited(section, option)) {
+                    Some(v) => Ok(v.get_raw()),
+                    None => self.get_default(option, FetchErrorKind::NoSuchOption)
+                }
+            },
+            None => self.get_default(option, FetchErrorKind::NoSuchSection)
+        }
+    }
+
+    ///
+    /// Returns true if section `section` contains an option `option`
+    /// (or if there is a default option called `option`). If the
+    /// section does not exist, `FetchError::NoSuchSection` is
+    /// returned as an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_str(
+    ///      "[foo]\n\
+    ///       bar : quux\n", &[]);
+    /// let ho = cp.has_option("foo", "bar");
+    /// assert!(ho.is_ok() && ho.unwrap() == true);
+    /// let ho2 = cp.has_option("foo", "missing");
+    /// assert!(ho2.is_ok() && ho2.unwrap() == false);
+    /// ```
+    pub fn has_option(&self, section: &str, option: &str) -> Result<bool, FetchError> {
+        match self.props(section) {
+            Some(opts) => Ok(opts.contains_key(option) ||
+                             try!(self.inherited(section, option)).is_some() ||
+                             self.default_sect.contains_key(option) ||
+                             self.defaults.contains_key(option)),
+            None => Err(fe_error(FetchErrorKind::NoSuchSection))
+        }
+    }
+
+    ///
+    /// Sets (or with `None`, clears) the parent of a section, from
+    /// which it inherits options it does not set itself. This is the
+    /// same as a `[section : parent]` header, and is written out as
+    /// one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let mut cp = ConfigParser::from_str("[base]\nport = 5432\n[replica]\n", &[]);
+    /// cp.set_parent("replica", Some("base"));
+    /// assert_eq!(cp.get("replica", "port").unwrap(), "5432");
+    /// ```
+    ///
+    pub fn set_parent(&mut self, section: &str, parent: Option<&str>) {
+        match parent {
+            Some(p) => { self.parents.insert(section.to_string(), p.to_string()); },
+            None => { self.parents.remove(section); }
+        }
+    }
+
+    ///
+    /// Returns the section from which `section` inherits, either by
+    /// declaration, or (with the `dotted_inheritance` option) by its
+    /// name.
+    ///
+    pub fn parent(&self, section: &str) -> Option<String> {
+        match self.parents.get(section) {
+            Some(p) => return Some(p.clone()),
+            None => {}
+        }
+        if self.opts.dotted_inheritance {
+            match ConfigParser::split_section_key(section) {
+                (ref name, None) => match name.rfind('.') {
+                    Some(i) => return Some(name[..i].to_string()),
+                    None => {}
+                },
+                _ => {} // subsections don't inherit by name
+            }
+        }
+        None
+    }
+
+    // the ancestors of a section, nearest first
+    fn ancestors(&self, section: &str) -> Result<Vec<String>, FetchError> {
+        let mut chain = vec![section.to_string()];
+        loop {
+            let parent = match self.parent(chain[chain.len()-1].as_slice()) {
+                Some(p) => p,
+                None => break
+            };
+            if chain.contains(&parent) {
+                chain.push(parent);
+                return Err(fe_error_detail(FetchErrorKind::InheritanceCircularity,
+                                           chain.connect(" -> ")))
+            }
+            chain.push(parent);
+        }
+        chain.remove(0);
+        Ok(chain)
+    }
+
+    // look for an option in the ancestors of a section
+    fn inherited(&self, section: &str, option: &str) -> Result<Option<&InterpString>, FetchError> {
+        for a in try!(self.ancestors(section)).iter() {
+            match self.sections.get(a).and_then(|opts| opts.get(option)) {
+                Some(v) => return Ok(Some(v)),
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+
+    // the value of an option, interpolated in the section asked for.
+    // Every option looked up in the course of one `get` is so in that
+    // section, so `resolved` can hold their values for the whole of it
+    fn get_interp(&self, section: &str, option: &str, expanded : &mut HashSet<String>,
+                  resolved : &mut HashMap<String, String>) -> Result<String, FetchError> {
+        match self.sections.get(section) {
+            Some(opts) => match opts.get(option) {
+                // inherited values are interpolated in the context of
+                // the section asked for, just like defaults
+                Some(v) => v.get_resolved(section, option, self, expanded, resolved),
+                None => match try!(self.inherited(section, option)) {
+                    Some(v) => v.get_resolved(section, option, self, expanded, resolved),
+                    None => self.get_default_interp(section, option, FetchErrorKind::NoSuchOption,
+                                                    expanded, resolved)
+                }
+            },
+            None => self.get_default_interp(section, option, FetchErrorKind::NoSuchSection,
+                                            expanded, resolved)
+        }
+    }
+
+    pub fn get(&self, section: &str, option: &str) -> Result<String, FetchError> {
+        let mut expanded : HashSet<String> = HashSet::new();
+        self.get_interp(section, option, &mut expanded, &mut HashMap::new())
+    }
+
+    ///
+    /// Fetches every value of a (multi-valued) option, each one
+    /// interpolated as with `get`. Options with a single value give
+    /// a one element vector.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::{ConfigParser,ParserOptions};
+    ///
+    /// let mut opts = ParserOptions::new();
+    /// opts.multi_value = true;
+    /// let mut cp = ConfigParser::with_options(&[], opts);
+    /// assert!(cp.read_str("[lb]\nport = 80\n\
+    ///                      server = a:%(port)s\n\
+    ///                      server = b:%(port)s\n").is_ok());
+    /// assert_eq!(cp.get_all("lb", "server").unwrap(),
+    ///            vec!["a:80".to_string(), "b:80".to_string()]);
+    /// assert_eq!(cp.get("lb", "server").unwrap(), "b:80");
+    /// ```
+    ///
+    pub fn get_all(&self, section: &str, option: &str) -> Result<Vec<String>, FetchError> {
+        let found = match self.sections.get(section) {
+            Some(opts) => match opts.get(option) {
+                Some(v) => Some(v),
+                None => try!(self.inherited(section, option))
+            },
+            None => None
+        };
+        match found.or_else(|| self.default_sect.get(option)) {
+            Some(v) => {
+                let mut expanded : HashSet<String> = HashSet::new();
+                v.get_all(section, option, self, &mut expanded)
+            },
+            // a default from the constructor, or an error
+            None => self.get(section, option).map(|v| vec![v])
+        }
+    }
+
+    ///
+    /// Tells where the value of an option, as `get` would find it,
+    /// was set: at a line of a source, with `set`, or as a default
+    /// given to the constructor. Options outside any section which
+    /// are collected into the defaults report the line they were
+    /// read at, like any other.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::{ConfigParser,Origin};
+    ///
+    /// let mut cp = ConfigParser::from_str("[app]\n\nport = 80\n", &[("level", "info")]);
+    /// cp.set("app", "name", "web");
+    /// assert_eq!(cp.origin("app", "port").unwrap(), Origin::Source("<string>".to_string(), 3));
+    /// assert_eq!(cp.origin("app", "name").unwrap(), Origin::Programmatic);
+    /// assert_eq!(cp.origin("app", "level").unwrap(), Origin::Default);
+    /// ```
+    ///
+    pub fn origin(&self, section: &str, option: &str) -> Result<Origin, FetchError> {
+        match self.sections.get(section) {
+            Some(opts) => match opts.get(option) {
+                Some(v) => Ok(v.origin()),
+                None => match try!(self.inherited(section, option)) {
+                    Some(v) => Ok(v.origin()),
+                    None => self.default_origin(option, FetchErrorKind::NoSuchOption)
+                }
+            },
+            None => self.default_origin(option, FetchErrorKind::NoSuchSection)
+        }
+    }
+
+    fn default_origin(&self, option: &str, fe: FetchErrorKind) -> Result<Origin, FetchError> {
+        match self.default_sect.get(option) {
+            Some(v) => Ok(v.origin()),
+            None => match self.defaults.contains_key(option) {
+                true => Ok(Origin::Default),
+                false => Err(fe_error(fe))
+            }
+        }
+    }
+
+    // the error for a value which cannot be converted, saying where
+    // the value came from
+    fn invalid_literal(&self, section: &str, option: &str, v: &str) -> FetchError {
+        let detail = match self.origin(section, option) {
+            Ok(o) => format!("{} = {} in [{}], from {}", option, v, section, o),
+            Err(_) => format!("{} = {} in [{}]", option, v, section)
+        };
+        fe_error_detail(FetchErrorKind::InvalidLiteral, detail)
+    }
+
+    // Now I wish Rust had default param values - having a boolean
+    // 'raw' would be handy here, to avoid the attempt to interpolate.
+    pub fn getboolean(&self, section: &str, option: &str) -> Result<bool, FetchError> {
+        match self.get(section, option) {
+            Err(e) => Err(e),
+            Ok(v) => match bool_literal(v.as_slice()) {
+                Some(b) => Ok(b),
+                None => Err(self.invalid_literal(section, option, v.as_slice()))
+            }
+        }
+    }
+
+    pub fn getuint(&self, section: &str, option: &str) -> Result<usize, FetchError> {
+        match self.get(section, option) {
+            Err(e) => Err(e),
+            Ok(v) => match number_literal(v.as_slice(), 1) {
+                Some(u) => Ok(u),
+                None => Err(self.invalid_literal(section, option, v.as_slice()))
+            }
+        }
+    }
+
+    pub fn getint(&self, section: &str, option: &str) -> Result<isize, FetchError> {
+        match self.get(section, option) {
+            Err(e) => Err(e),
+            Ok(v) => match number_literal(v.as_slice(), 1) {
+                Some(i) => Ok(i),
+                None => Err(self.invalid_literal(section, option, v.as_slice()))
+            }
+        }
+    }
+
+    pub fn getfloat(&self, section: &str, option: &str) -> Result<f64, FetchError> {
+        match self.get(section, option) {
+            Err(e) => Err(e),
+            Ok(v) => match number_literal(v.as_slice(), 1.0f64) {
+                Some(f) => Ok(f),
+                None => Err(self.invalid_literal(section, option, v.as_slice()))
+            }
+        }
+    }
+
+    ///
+    /// Lists the sections, in the order they were first read or
+    /// added
+    ///
+    pub fn sections(&self) -> slice::Iter<String> {
+        self.sections.keys()
+    }
+
+    ///
+    /// Lists, in sorted order, the subsections of the sections called
+    /// `name`, as in git-config files.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::ConfigParser;
+    ///
+    /// let cp = ConfigParser::from_str("[remote \"origin\"]\n[remote \"backup\"]\n\
+    ///                                  [remote]\n[branch \"main\"]\n", &[]);
+    /// assert_eq!(cp.subsections("remote"), vec!["backup".to_string(), "origin".to_string()]);
+    /// ```
+    ///
+    pub fn subsections(&self, name: &str) -> Vec<String> {
+        let mut subs : Vec<String> = self.sections.keys().filter_map(|k| {
+            match ConfigParser::split_section_key(k.as_slice()) {
+                (ref n, Some(ref sub)) if n.as_slice() == name => Some(sub.clone()),
+                _ => None
+            }
+        }).collect();
+        subs.sort();
+        subs
+    }
+
+    ///
+    /// Lists the options of a section, with their values, in the order
+    /// they were first read or set
+    ///
+    pub fn options(&self, section: &str) -> Result<Iter<InterpString>, FetchError> {
+        match self.props(section) {
+            Some(opts) =>  Ok(opts.iter()),
+            None=> Err(fe_error(FetchErrorKind::NoSuchSection))
+        }
+    }
+}
+
+// an option of a `ConfigRef`, with the line it was set at
+struct RefOption<'a> {
+    key : Cow<'a, str>,
+    value : Cow<'a, str>,
+    line : usize
+}
+
+// the options of a `ConfigRef` section, in the order they were read,
+// and where each key is among them
+struct RefProps<'a> {
+    opts : Vec<RefOption<'a>>,
+    index : HashMap<Cow<'a, str>, usize>
+}
+
+impl<'a> RefProps<'a> {
+    fn new() -> RefProps<'a> {
+        RefProps { opts : vec![], index : HashMap::new() }
+    }
+
+    fn get(&self, key : &str) -> Option<&RefOption<'a>> {
+        match self.index.get(key) {
+            Some(&i) => Some(&self.opts[i]),
+            None => None
+        }
+    }
+
+    // set an option; a replaced option keeps its place
+    fn insert(&mut self, opt : RefOption<'a>) {
+        let found = self.index.get(opt.key.as_slice()).map(|&i| i);
+        match found {
+            Some(i) => self.opts[i] = opt,
+            None => {
+                self.index.insert(opt.key.clone(), self.opts.len());
+                self.opts.push(opt);
+            }
+        }
+    }
+}
+
+///
+/// A read-only view of configuration text, whose section names, keys
+/// and values are borrowed from the text rather than copied. Only
+/// values built from backslash continuations, and section names
+/// whose subsection is not written in canonical form, need their own
+/// storage. Use it to read many small configurations cheaply; it
+/// reads text just as `ConfigParser::from_str` does (with the
+/// default `ParserOptions`), and `to_parser` gives the equivalent
+/// `ConfigParser` when one is needed. Text from untrusted sources
+/// should be read with `with_limits`.
+///
+/// # Example
+///
+/// ```
+/// use conparse::conparse::ConfigRef;
+///
+/// let text = "[server]\nhost = example.org\nport = 8080\nurl = http://%(host)s/\n";
+/// let cr = ConfigRef::from_str(text, &[("debug", "off")]);
+/// assert_eq!(cr.get_raw("server", "host").unwrap(), "example.org");
+/// assert_eq!(cr.get("server", "url").unwrap().as_slice(), "http://example.org/");
+/// assert_eq!(cr.getuint("server", "port").unwrap(), 8080);
+/// assert_eq!(cr.getboolean("server", "debug").unwrap(), false);
+/// let cp = cr.to_parser();
+/// assert_eq!(cp.get("server", "url").unwrap(), "http://example.org/");
+/// ```
+///
+pub struct ConfigRef<'a> {
+    defaults : Vec<(&'a str, &'a str)>,
+    sections : Vec<(Cow<'a, str>, RefProps<'a>)>,
+    // where each section is in `sections`
+    index : HashMap<Cow<'a, str>, usize>,
+    default_sect : RefProps<'a>,
+    limits : Limits
+}
+
+// The logical lines of some text, as `read_numbered_line` reads them,
+// but borrowed from the text where they can be: each line, and the
+// line number it starts at once any comment lines are skipped. Lines
+// over the length limits are an error, which ends the text.
+struct RefLines<'a> {
+    text : &'a str,
+    lineno : usize,
+    opts : ParserOptions
+}
+
+impl<'a> RefLines<'a> {
+    fn new(text : &'a str, opts : &ParserOptions) -> RefLines<'a> {
+        RefLines { text : strip_bom(text), lineno : 0, opts : opts.clone() }
+    }
+
+    fn too_long(&mut self, what : &str, max : usize, line : usize) -> FetchError {
+        self.text = "";
+        fe_error_detail(FetchErrorKind::LimitExceeded,
+                        format!("{} line over {} bytes at line {} of {}",
+                                what, max, line, STRING_SOURCE))
+    }
+}
+
+impl<'a> Iterator for RefLines<'a> {
+    type Item = Result<(Cow<'a, str>, usize), FetchError>;
+
+    fn next(&mut self) -> Option<Result<(Cow<'a, str>, usize), FetchError>> {
+        if self.text.is_empty() {
+            return None
+        }
+        let max_line = self.opts.limits.max_line_length;
+        let max_logical = self.opts.limits.max_logical_line_length;
+        let start = self.lineno + 1;
+        let mut joiner = LineJoiner::new(&self.opts);
+        loop {
+            if let Some(max) = max_logical {
+                if joiner.len() > max {
+                    return Some(Err(self.too_long("logical", max, start)))
+                }
+            }
+            if self.text.is_empty() {
+                if joiner.continuing {
+                    // a continuation at the end of the text is dropped
+                    return None
+                }
+                break;
+            }
+            let text : &'a str = self.text;
+            let (len, end) = match text.find('\n') {
+                Some(i) => (i, i + 1),
+                None => (text.len(), text.len())
+            };
+            if let Some(max) = max_line {
+                if len > max {
+                    return Some(Err(self.too_long("physical", max, start)))
+                }
+            }
+            let (l, newline) = split_line_end(&text[..end]);
+            self.text = &text[end..];
+            match joiner.push(Cow::Borrowed(l), newline) {
+                Joined::More => {},
+                Joined::Done => break,
+                Joined::Dangling => return None
+            }
+        }
+        if let Some(max) = max_logical {
+            if joiner.len() > max {
+                return Some(Err(self.too_long("logical", max, start)))
+            }
+        }
+        let line = self.lineno + joiner.skipped + 1;
+        self.lineno += joiner.lines;
+        Some(Ok((joiner.text(), line)))
+    }
+}
+
+impl<'a> ConfigRef<'a> {
+    ///
+    /// Reads configuration text, with defaults for options no section
+    /// sets, as `ConfigParser::from_str` does. Nothing limits how much
+    /// it reads or how far values are expanded, so text from
+    /// untrusted sources must be read with `with_limits` instead.
+    ///
+    pub fn from_str(s : &'a str, kvdefaults : &[(&'a str, &'a str)]) -> ConfigRef<'a> {
+        match ConfigRef::with_limits(s, kvdefaults, Limits::new()) {
+            Ok(cr) => cr,
+            Err(_) => unreachable!() // there is no limit to go over
+        }
+    }
+
+    ///
+    /// Reads configuration text as `from_str` does, but keeping to
+    /// `limits` while reading it and when interpolating its values,
+    /// as a `ConfigParser` with those `limits` would. Going over one
+    /// while reading is a `LimitExceeded` error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use conparse::conparse::{ConfigRef,FetchErrorKind,Limits};
+    ///
+    /// let mut limits = Limits::untrusted();
+    /// limits.max_sections = Some(1);
+    /// let cr = ConfigRef::with_limits("[a]\nx = 1\n", &[], limits.clone()).unwrap();
+    /// assert_eq!(cr.get_raw("a", "x").unwrap(), "1");
+    /// match ConfigRef::with_limits("[a]\n[b]\n", &[], limits) {
+    ///     Ok(_) => panic!(),
+    ///     Err(e) => assert_eq!(e.kind(), FetchErrorKind::LimitExceeded)
+    /// }
+    /// ```
+    ///
+    pub fn with_limits(s : &'a str, kvdefaults : &[(&'a str, &'a str)],
+                       limits : Limits) -> Result<ConfigRef<'a>, FetchError> {
+        let mut opts = ParserOptions::new();
+        opts.limits = limits;
+        let mut cr = ConfigRef{ defaults : kvdefaults.to_vec(), sections : vec![],
+                                index : HashMap::new(), default_sect : RefProps::new(),
+                                limits : opts.limits.clone() };
+        let mut lexer = Lexer::new(&opts);
+        // how many options there are, against the limit
+        let mut options = kvdefaults.len();
+        // the section options go to: None outside any section, where
+        // they are discarded, and Some(None) in the default section
+        let mut curr : Option<Option<usize>> = None;
+        for next in RefLines::new(s, &opts) {
+            let (ll, line) = try!(next);
+            match lexer.lex(ll, line) {
+                Event::SectionStart{ name, .. } => {
+                    if opts.is_default_section(name.as_slice()) {
+                        curr = Some(None);
+                        continue;
+                    }
+                    if let Some(max) = opts.limits.max_sections {
+                        if cr.sections.len() >= max && cr.props(name.as_slice()).is_none() {
+                            return Err(fe_error_detail(FetchErrorKind::LimitExceeded,
+                                                       format!("section [{}] in {} at line {} \
+                                                                is over the limit of {}",
+                                                               name, STRING_SOURCE, line, max)))
+                        }
+                    }
+                    curr = Some(Some(cr.section_index(name)));
+                },
+                Event::Option{ key, value, line, .. } => {
+                    let props = match curr {
+                        None => {
+                            warn!("Attempting to set option [{}, {}] outside of section - ignoring",
+                                  key, value);
+                            continue;
+                        },
+                        Some(None) => &mut cr.default_sect,
+                        Some(Some(i)) => &mut cr.sections[i].1
+                    };
+                    if let Some(max) = opts.limits.max_options {
+                        if props.get(key.as_slice()).is_none() {
+                            if options >= max {
+                                return Err(fe_error_detail(FetchErrorKind::LimitExceeded,
+                                                           format!("option {} in {} at line {} \
+                                                                    is over the limit of {}",
+                                                                   key, STRING_SOURCE, line, max)))
+                            }
+                            options += 1;
+                        }
+                    }
+                    props.insert(RefOption{ key : key, value : value, line : line });
+                },
+                Event::Invalid{ text, line, .. } => {
+                    warn!("Ignoring malformed line {} of {}: {}", line, STRING_SOURCE, text);
+                },
+                // the default options give nothing else but blank lines
+                _ => {}
+            }
+        }
+        Ok(cr)
+    }
+
+    // where a section is, adding it if it is new
+    fn section_index(&mut self, name : Cow<'a, str>) -> usize {
+        match self.index.get(name.as_slice()) {
+            Some(&i) => return i,
+            None => {}
+        }
+        let i = self.sections.len();
+        self.index.insert(name.clone(), i);
+        self.sections.push((name, RefProps::new()));
+        i
+    }
+
+    fn props(&self, section : &str) -> Option<&RefProps<'a>> {
+        match self.index.get(section) {
+            Some(&i) => Some(&self.sections[i].1),
+            None => None
+        }
+    }
+
+    fn get_default(&self, option : &str, fe : FetchErrorKind) -> Result<&str, FetchError> {
+        match self.default_sect.get(option) {
+            Some(o) => return Ok(o.value.as_slice()),
+            None => {}
+        }
+        match self.defaults.iter().find(|&&(k, _)| k == option) {
+            Some(&(_, v)) => Ok(v),
+            None => Err(fe_error(fe))
+        }
+    }
+
+    ///
+    /// Fetches the value of an option without interpolation, as
+    /// `ConfigParser::get_raw` does, but as a slice of the text read
+    ///
+    pub fn get_raw(&self, section : &str, option : &str) -> Result<&str, FetchError> {
+        match self.props(section) {
+            Some(opts) => match opts.get(option) {
+                Some(o) => Ok(o.value.as_slice()),
+                None => self.get_default(option, FetchErrorKind::NoSuchOption)
+            },
+            None => self.get_default(option, FetchErrorKind::NoSuchSection)
+        }
+    }
+
+    ///
+    /// Fetches the value of an option, interpolated as
+    /// `ConfigParser::get` does. Values with nothing to interpolate
+    /// are borrowed, not copied.
+    ///
+    pub fn get(&self, section : &str, option : &str) -> Result<Cow<str>, FetchError> {
+        let mut expanded : HashSet<String> = HashSet::new();
+        self.get_interp(section, option, &mut expanded, &mut HashMap::new())
+    }
+
+    fn get_interp(&self, section : &str, option : &str, expanded : &mut HashSet<String>,
+                  resolved : &mut HashMap<String, String>) -> Result<Cow<str>, FetchError> {
+        match self.props(section) {
+            Some(opts) => match opts.get(option) {
+                Some(o) => self.interpolate(o.value.as_slice(), section, option,
+                                            expanded, resolved),
+                None => self.get_default_interp(section, option, FetchErrorKind::NoSuchOption,
+                                                expanded, resolved)
+            },
+            None => self.get_default_interp(section, option, FetchErrorKind::NoSuchSection,
+                                            expanded, resolved)
+        }
+    }
+
+    // as get_default, but values from the default section are
+    // interpolated in the context of the section asked for
+    fn get_default_interp(&self, section : &str, option : &str, fe : FetchErrorKind,
+                          expanded : &mut HashSet<String>,
+                          resolved : &mut HashMap<String, String>)
+                          -> Result<Cow<str>, FetchError> {
+        match self.default_sect.get(option) {
+            Some(o) => self.interpolate(o.value.as_slice(), section, option, expanded, resolved),
+            None => self.get_default(option, fe).map(|v| Cow::Borrowed(v))
+        }
+    }
+
+    fn interpolate<'b>(&'b self, raw : &'b str, sec : &str, option : &str,
+                       expanded : &mut HashSet<String>,
+                       resolved : &mut HashMap<String, String>)
+                       -> Result<Cow<'b, str>, FetchError> {
+        if find_interp(raw).is_none() {
+            return Ok(Cow::Borrowed(raw))
+        }
+        interpolate_refs(raw, option, expanded, resolved, &self.limits, |oname, exp, res| {
+            self.get_interp(sec, oname, exp, res).map(|v| v.into_owned())
+        }).map(|v| Cow::Owned(v))
+    }
+
+    ///
+    /// Tells where the value of an option, as `get` would find it,
+    /// was set, as `ConfigParser::origin` does
+    ///
+    pub fn origin(&self, section : &str, option : &str) -> Result<Origin, FetchError> {
+        let found = match self.props(section) {
+            Some(opts) => opts.get(option),
+            None => None
+        };
+        match found.or_else(|| self.default_sect.get(option)) {
+            Some(o) => Ok(Origin::Source(STRING_SOURCE.to_string(), o.line)),
+            None => self.get_raw(section, option).map(|_| Origin::Default)
+        }
+    }
+
+    fn invalid_literal(&self, section : &str, option : &str, v : &str) -> FetchError {
+        let detail = match self.origin(section, option) {
+            Ok(o) => format!("{} = {} in [{}], from {}", option, v, section, o),
+            Err(_) => format!("{} = {} in [{}]", option, v, section)
+        };
+        fe_error_detail(FetchErrorKind::InvalidLiteral, detail)
+    }
+
+    pub fn getboolean(&self, section : &str, option : &str) -> Result<bool, FetchError> {
+        let v = try!(self.get(section, option));
+        match bool_literal(v.as_slice()) {
+            Some(b) => Ok(b),
+            None => Err(self.invalid_literal(section, option, v.as_slice()))
+        }
+    }
+
+    pub fn getuint(&self, section : &str, option : &str) -> Result<usize, FetchError> {
+        let v = try!(self.get(section, option));
+        match number_literal(v.as_slice(), 1) {
+            Some(u) => Ok(u),
+            None => Err(self.invalid_literal(section, option, v.as_slice()))
+        }
+    }
+
+    pub fn getint(&self, section : &str, option : &str) -> Result<isize, FetchError> {
+        let v = try!(self.get(section, option));
+        match number_literal(v.as_slice(), 1) {
+            Some(i) => Ok(i),
+            None => Err(self.invalid_literal(section, option, v.as_slice()))
+        }
+    }
+
+    pub fn getfloat(&self, section : &str, option : &str) -> Result<f64, FetchError> {
+        let v = try!(self.get(section, option));
+        match number_literal(v.as_slice(), 1.0f64) {
+            Some(f) => Ok(f),
+            None => Err(self.invalid_literal(section, option, v.as_slice()))
+        }
+    }
+
+    ///
+    /// Lists the sections, in the order they were first read
+    ///
+    pub fn sections(&self) -> Vec<&str> {
+        self.sections.iter().map(|&(ref n, _)| n.as_slice()).collect()
+    }
+
+    ///
+    /// Builds a `ConfigParser` holding copies of the same sections,
+    /// options and defaults, as `ConfigParser::from_str` would have
+    /// from the same text
+    ///
+    pub fn to_parser(&self) -> ConfigParser {
+        let mut cp = ConfigParser::new(self.defaults.as_slice());
+        let all = Some((None, &self.default_sect)).into_iter().chain(
+            self.sections.iter().map(|&(ref n, ref p)| (Some(n.as_slice()), p)));
+        for (name, opts) in all {
+            let props = match name {
+                Some(n) => cp.props_or_create(n),
+                None => &mut cp.default_sect
+            };
+            for o in opts.opts.iter() {
+                let mut is = InterpString::new(o.value.as_slice());
+                is.origin = Origin::Source(STRING_SOURCE.to_string(), o.line);
+                props.insert(o.key.to_string(), is);
+            }
+        }
+        cp
+    }
+}
+
+#[cfg(test)]
+
+mod test {
+    extern crate env_logger;
+
+    use conparse::*;
+    use std::old_io::{MemReader,IoErrorKind,TempDir,File,Open,ReadWrite,IoResult};
+    use std::str::from_utf8;
+    use std::borrow::Cow;
+
+    // a parser with the given defaults, after `f` has set its options
+    fn parser_with<F : FnOnce(&mut ParserOptions)>(defaults : &[(&str, &str)], f : F)
+                                                   -> ConfigParser {
+        let mut opts = ParserOptions::new();
+        f(&mut opts);
+        ConfigParser::with_options(defaults, opts)
+    }
+
+    #[test]
+    fn check_default() {
+        env_logger::init().unwrap();
+
+        let rp = ConfigParser::new(&[( "t1", "v1"), ("t2", "v2")]);
+        assert!(rp.defaults.contains_key("t1"));
+        assert_eq!(rp.defaults.get("t1").unwrap().as_slice(), "v1");
+        assert_eq!(rp.defaults.len(), 2)
+    }
+
+    #[test]
+    fn set_option() {
+        let mut rp = ConfigParser::new(&[( "t1", "v1"), ("t2", "v2")]);
+        rp.set("global", "t1", "sv1");
+        assert_eq!(rp.get("global", "t1").ok().unwrap(), "sv1");
+        assert_eq!(rp.get("global", "t2").ok().unwrap(), "v2");
+        let mut r = rp.get("no-section", "t3");
+        assert!(r.is_err() && r.err().unwrap().kind() == FetchErrorKind::NoSuchSection);
+        r = rp.get("global", "t3");
+        assert!(r.is_err() && r.err().unwrap().kind() == FetchErrorKind::NoSuchOption);
+    }
+
+    #[test]
+    fn read_strings() {
+        let tinput = "One \\\n\\\n     Two\n\n#comment \nThree\nFour";
+        let mut v = MemReader::new(tinput.as_bytes().to_vec());
+        assert!(! v.eof());
+        let br = v.read_continued_line();
+        assert_eq!(br.unwrap().as_slice().trim(), "One Two");
+        let br = v.read_continued_line();
+        assert_eq!(br.unwrap().as_slice().trim(), "");
+        let br = v.read_continued_line();
+        assert_eq!(br.unwrap().as_slice().trim(), "Three");
+        let br = v.read_continued_line();
+        assert_eq!(br.unwrap().as_slice().trim(), "Four");
+        let br = v.read_continued_line();
+        assert_eq!(br.err().unwrap().kind, IoErrorKind::EndOfFile);
+
+    }
+
+    #[test]
+    fn read_indented_comments() {
+        let tinput = "One\n    # indented comment\n\t; tabbed comment\n   \t \nTwo";
+        let mut v = MemReader::new(tinput.as_bytes().to_vec());
+        assert_eq!(v.read_continued_line().unwrap().as_slice(), "One\n");
+        assert_eq!(v.read_continued_line().unwrap().as_slice(), "\n");
+        assert_eq!(v.read_continued_line().unwrap().as_slice(), "Two\n");
+
+        let cp = ConfigParser::from_str("[block]\n  a = 1\n  # b = 2\n  ; c = 3\n  d = 4\n", &[]);
+        assert!(cp.has_option("block", "a").unwrap());
+        assert!(! cp.has_option("block", "# b").unwrap());
+        assert!(! cp.has_option("block", "b").unwrap());
+        assert!(! cp.has_option("block", "; c").unwrap());
+        assert_eq!(cp.get("block", "d").unwrap(), "4");
+    }
+
+    #[test]
+    fn read_comment_in_continuation() {
+        let tinput = "a = one \\\n  # stop here\n  b = two\n";
+        let mut v = MemReader::new(tinput.as_bytes().to_vec());
+        assert_eq!(v.read_continued_line().unwrap().as_slice(), "a = one \n");
+        assert_eq!(v.read_continued_line().unwrap().as_slice(), "  b = two\n");
+
+        let mut opts = ParserOptions::new();
+        opts.comments_in_continuations = true;
+        let mut v = MemReader::new(tinput.as_bytes().to_vec());
+        assert_eq!(v.read_logical_line(&opts).unwrap().as_slice(), "a = one b = two\n");
+    }
+
+    #[test]
+    fn read_iterated_strings() {
+        let cp = ConfigParser::from_strs( &["foo = quux\n  [sec1] \nfoo =  bar",
+                                            "[sec2]\nfoo : wibble"], &[]);
+        assert!(cp.sections.contains_key("sec1"));
+        assert!(cp.sections.contains_key("sec2"));
+        let ocs = cp.sections.get("sec1");
+        assert!(ocs.is_some());
+        let cs = ocs.unwrap();
+        assert!(cs.contains_key("foo"));
+        let ocv = cs.get("foo");
+        assert!(ocv.is_some());
+        let cv = ocv.unwrap();
+        assert_eq!("bar", cv.get_raw().as_slice());
+        let ocv2 = cp.get("sec2", "foo");
+        assert!(ocv2.is_ok());
+        let cv2 = ocv2.unwrap();
+        assert_eq!("wibble", cv2.as_slice());
+    }
+
+    #[test]
+    fn read_sections() {
+        let cp = ConfigParser::from_str("foo = quux\n  [Zulu] \n\
+                 foo =  bar\n  [ Alpha ] \nfoo : wibble", &[]);
+        let mut ks : Vec<&String> = cp.sections().collect();
+        ks.sort();
+
+        assert_eq!(ks, vec![&"Alpha", &"Zulu"]);
+    }
+
+    #[test]
+    fn read_odd_section_names() {
+        let cp = ConfigParser::from_str("[server.eu-west]\nhost = eu1\n\
+                 [ My Service ] ; spaces inside\nport = 80\n\
+                 [a:b=c(d)]\nx = y\n", &[]);
+        let mut ks : Vec<&String> = cp.sections().collect();
+        ks.sort();
+        assert_eq!(ks, vec![&"My Service", &"a:b=c(d)", &"server.eu-west"]);
+        assert_eq!(cp.get("server.eu-west", "host").unwrap(), "eu1");
+        assert_eq!(cp.get("My Service", "port").unwrap(), "80");
+
+        // and they should survive a trip through the writer
+        let out = cp.to_string().unwrap();
+        assert_eq!(out, "[server.eu-west]\nhost : eu1\n\n[My Service]\nport : 80\n\n\
+                         [a:b=c(d)]\nx : y\n\n");
+        let cp2 = ConfigParser::from_str(out.as_slice(), &[]);
+        let mut ks2 : Vec<&String> = cp2.sections().collect();
+        ks2.sort();
+        assert_eq!(ks, ks2);
+        assert_eq!(cp2.get("a:b=c(d)", "x").unwrap(), "y");
+    }
+
+    #[test]
+    fn read_options() {
+        let cp = ConfigParser::from_str(
+            "foo = quux\n  \
+             [Zulu] \n\
+             foo =  bar\n\
+             [ Alpha ] \n\
+             foo : wibble\n\
+             standalone\n\
+             \n\
+             bar = quux  ", &[]);
+        let os = cp.options("NotHere");
+        assert!(os.is_err() && os.err().unwrap().kind() == FetchErrorKind::NoSuchSection);
+        let os2 = cp.options("Alpha");
+        assert!(os2.is_ok());
+        assert!(cp.has_option("Alpha", "standalone").unwrap());
+        let mut opts : Vec<(&String,&InterpString)> = os2.unwrap().collect();
+        opts.sort_by(|&(k1,_),&(k2,_)| k1.cmp(k2));
+
+        let ev = [("bar","quux"), ("foo","wibble"), ("standalone", "")];
+        assert_eq!(opts.len(), ev.len());
+        for (&(k1,v1),&(k2,v2)) in opts.iter().zip(ev.iter()) {
+            assert_eq!(k1.as_slice(),k2);
+            assert_eq!(v1.get_raw().as_slice(),v2);
+        }
     }
 
-    ///
-    /// sets an option to a given value, inside a given section
-    /// if the section does not exist, it will be created. If the
-    /// option within the section already exists, it will be
-    /// overwritten. Returns no result.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let mut cp = ConfigParser::new(&[]);
-    /// cp.set("mysection", "myoption", "myvalue" );
-    /// ```
-    ///
-    pub fn set(&mut self, section: &str, option: &str, value: &str) -> () {
-        match self.sections.entry(section.to_string()) {
-            Entry::Occupied(mut o) => {
-                o.get_mut().insert(option.to_string(), InterpString::new(value));
-            },
-            Entry::Vacant(v) => {
-                let mut opts = HashMap::new();
-                opts.insert(option.to_string(), InterpString::new(value));
-                v.insert(opts);
+    // utility function to create a temp directory
+    // which can be closed afterwards to zap it.
+
+    fn new_tmp_dir() -> IoResult<Box<TempDir>> {
+        match TempDir::new("conparse") {
+            Ok(dir) => Ok(Box::new(dir)),
+            Err(e) => {
+                error!("Cannot create temporary directory: {}", e);
+                Err(e)
             }
         }
     }
 
-    ///
-    /// Deletes an option from a given section
-    /// If the option does not exist, `FetchError::NoSuchOption` is returned as error
-    /// If the section does not exist, `FetchError::NoSuchSection` is
-    /// returned as error
-    /// On successful completion, `()` is returned as `Ok` value
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let mut cp = ConfigParser::new(&[]);
-    ///
-    /// cp.set("foosection", "baroption", "quuxval");
-    /// assert!(cp.get("foosection", "baroption").is_ok());
-    /// cp.remove_option("foosection", "baroption");
-    /// assert!(cp.get("foosection", "baroption").is_err());
-    /// ```
-    pub fn remove_option(&mut self, section : &str, option: &str) -> Result<(),FetchError> {
-        match self.sections.get_mut(section) {
-            Some(opts) => {
-                match opts.remove(option) {
-                    Some(_) => Ok(()),
-                    None => Err(fe_error(FetchErrorKind::NoSuchOption))
+    // utility function to create a temporary directory and a file
+    // within it containing the contents of 's', and return the tuple
+    // of (TempDir,path-to-file). On closing the temp dir, the
+    // contents are deleted.
+    
+    fn write_file<'a>(s: &'a str, fname: &'a str) -> IoResult<(Box<TempDir>,Path)> {
+        let tmpdir = new_tmp_dir().unwrap();
+        let mut tmppath = Path::new(tmpdir.path());
+        tmppath.push(fname);
+        match File::open_mode(&tmppath, Open, ReadWrite) {
+            Ok(mut f) => {
+                match f.write_str(s) {
+                    Ok(_) => {Ok((tmpdir,tmppath))},
+                    Err(e) => {error!("Failed to write to file {} : {}", tmppath.display(), e);
+                               Err(e)
+                    }
                 }
             },
-            None => Err(fe_error(FetchErrorKind::NoSuchSection))
+            Err(e) => {
+                error!("Unable to write to temporary file {} : {}", tmppath.display(), e);
+                Err(e)
+            }
         }
     }
 
-    fn get_default(&self, option: &str, fe: FetchErrorKind) -> Result<String, FetchError> {
-        match self.defaults.get(option) {
-            Some(v) => Ok(v.clone()),
-            None => Err(fe_error(fe))
-        }
+    #[test]
+    fn read_file() {
+        let rtp = write_file("foo = quux\n  [Zulu] \n\
+             foo =  bar\n  [ Alpha ] \nfoo : wibble", "test_rf.ini");
+        assert!(rtp.is_ok());
+        let (td,tp) = rtp.unwrap();
+        info!("Written config file to {}", tp.display());
+        let cp = ConfigParser::from_file(tp.as_str().unwrap(), &[]);
+        assert_eq!(cp.get("Zulu", "foo").unwrap(), "bar");
+        assert_eq!(cp.get("Alpha", "foo").unwrap(), "wibble");
+        assert!(td.close().is_ok());
     }
 
-    ///
-    /// Fetches a given option from a given section, and returns the
-    /// value of the option *without* interpolation. If the section
-    /// does not exist `FetchError::NoSuchSection` is returned as
-    /// error; if the option does not exist,
-    /// `FetchError::NoSuchOption` is returned as error. Otherwise the
-    /// raw option is returned as a `String`.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let cp =
-    /// ConfigParser::from_str("[foo]\n\
-    ///     bar=quux\n\
-    ///     bar2=%(bar)s is fun\n", &[]);
-    ///
-    /// let mut b2 = cp.get("foo", "bar2");
-    /// assert!(b2.is_ok() && b2.unwrap().as_slice() == "quux is fun"); // interpolate
-    /// let mut b2 = cp.get_raw("foo", "bar2");
-    /// assert!(b2.is_ok() && b2.unwrap().as_slice() == "%(bar)s is fun");
-    /// // no interpolation with get_raw
-    /// ```
-    pub fn get_raw(&self, section: &str, option: &str) -> Result<String, FetchError> {
-        match self.sections.get(section) {
-            Some(opts) => match opts.get(option) {
-                Some(v) => Ok(v.get_raw()),
-                None => self.get_default(option, FetchErrorKind::NoSuchOption)
-            },
-            None => self.get_default(option, FetchErrorKind::NoSuchSection)
-        }
+    // write a file into an existing temporary directory
+    fn write_into(dir: &Path, fname: &str, s: &str) -> Path {
+        let p = dir.join(fname);
+        let mut f = File::open_mode(&p, Open, ReadWrite).unwrap();
+        assert!(f.write_str(s).is_ok());
+        p
     }
 
-    ///
-    /// Returns true if section `section` contains an option `option`
-    /// (or if there is a default option called `option`). If the
-    /// section does not exist, `FetchError::NoSuchSection` is
-    /// returned as an error.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use conparse::conparse::ConfigParser;
-    ///
-    /// let cp = ConfigParser::from_str(
-    ///      "[foo]\n\
-    ///       bar : quux\n", &[]);
-    /// let ho = cp.has_option("foo", "bar");
-    /// assert!(ho.is_ok() && ho.unwrap() == true);
-    /// let ho2 = cp.has_option("foo", "missing");
-    /// assert!(ho2.is_ok() && ho2.unwrap() == false);
-    /// ```
-    pub fn has_option(&self, section: &str, option: &str) -> Result<bool, FetchError> {
-        match self.sections.get(section) {
-            Some(opts) => Ok(opts.contains_key(option) || self.defaults.contains_key(option)),
-            None => Err(fe_error(FetchErrorKind::NoSuchSection))
-        }
+    #[test]
+    fn test_includes() {
+        let td = new_tmp_dir().unwrap();
+        let conf_d = td.path().join("conf.d");
+        assert!(::std::old_io::fs::mkdir(&conf_d, ::std::old_io::USER_RWX).is_ok());
+        let main = write_into(td.path(), "main.ini",
+                              "[app]\nname = main\nport = 80\n\
+                               include = conf.d/db.ini\n\
+                               level = info\n");
+        write_into(&conf_d, "db.ini", "[db]\nhost = db1\n!include port.ini\n");
+        write_into(&conf_d, "port.ini", "[app]\nport = 8080\n");
+
+        let mut cp = parser_with(&[], |o| o.allow_includes = true);
+        assert!(cp.read_file(main.as_str().unwrap()).is_ok());
+        assert_eq!(cp.get("db", "host").unwrap(), "db1");
+        assert_eq!(cp.get("app", "port").unwrap(), "8080");
+        // the including file carries on in its own section
+        assert_eq!(cp.get("app", "level").unwrap(), "info");
+        assert!(cp.get("db", "level").is_err());
+
+        // relative to the current directory outside of a file
+        let mut cp2 = parser_with(&[], |o| o.allow_includes = true);
+        let inc = format!("!include {}\n", conf_d.join("db.ini").display());
+        assert!(cp2.read_str(inc.as_slice()).is_ok());
+        assert_eq!(cp2.get("app", "port").unwrap(), "8080");
+
+        // without the option, include is just another key
+        let cp3 = ConfigParser::from_file(main.as_str().unwrap(), &[]);
+        assert_eq!(cp3.get("app", "include").unwrap(), "conf.d/db.ini");
+        assert!(cp3.get("db", "host").is_err());
+        assert!(td.close().is_ok());
     }
 
-    fn get_interp(&self, section: &str, option: &str,
-                  expanded : &mut HashSet<String>) -> Result<String, FetchError> {
-        match self.sections.get(section) {
-            Some(opts) => match opts.get(option) {
-                Some(v) => v.get(section, option, self, expanded),
-                None => self.get_default(option, FetchErrorKind::NoSuchOption)
-            },
-            None => self.get_default(option, FetchErrorKind::NoSuchSection)
+    #[test]
+    fn test_include_errors() {
+        let td = new_tmp_dir().unwrap();
+        let a = write_into(td.path(), "a.ini", "[a]\nx = 1\ninclude = b.ini\n");
+        let b = write_into(td.path(), "b.ini", "[b]\n!include a.ini\n");
+        let mut cp = parser_with(&[], |o| o.allow_includes = true);
+        match cp.read_file(a.as_str().unwrap()) {
+            Ok(_) => assert!(false),
+            Err(e) => {
+                assert_eq!(e.kind(), FetchErrorKind::IncludeCircularity);
+                assert_eq!(e.detail().unwrap(),
+                           format!("{} -> {} -> {}", a.display(), b.display(), a.display()));
+            }
+        }
+
+        let mut opts = ParserOptions::new();
+        opts.allow_includes = true;
+        opts.max_include_depth = 1;
+        let c = write_into(td.path(), "c.ini", "include = d.ini\n");
+        write_into(td.path(), "d.ini", "include = e.ini\n");
+        write_into(td.path(), "e.ini", "[e]\nx = 1\n");
+        let mut cp2 = ConfigParser::with_options(&[], opts.clone());
+        match cp2.read_file(c.as_str().unwrap()) {
+            Ok(_) => assert!(false),
+            Err(e) => assert_eq!(e.kind(), FetchErrorKind::IncludeDepthExceeded)
         }
+        opts.max_include_depth = 2;
+        let mut cp3 = ConfigParser::with_options(&[], opts);
+        assert!(cp3.read_file(c.as_str().unwrap()).is_ok());
+        assert_eq!(cp3.get("e", "x").unwrap(), "1");
+        assert!(cp3.parse_errors().is_empty());
+
+        // a missing file is an error of the line including it
+        let f = write_into(td.path(), "f.ini", "[f]\nx = 1\ninclude = missing.ini\ny = 2\n");
+        let mut cp4 = parser_with(&[], |o| o.allow_includes = true);
+        assert!(cp4.read_file(f.as_str().unwrap()).is_ok());
+        assert_eq!(cp4.get("f", "y").unwrap(), "2");
+        let errs = cp4.parse_errors();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].kind(), ParseErrorKind::ReadError);
+        assert_eq!(errs[0].source(), format!("{}", f.display()));
+        assert_eq!(errs[0].line(), 3);
+        assert_eq!(errs[0].text(), "include = missing.ini");
+        assert!(errs[0].detail().unwrap().starts_with(
+            format!("{}: ", td.path().join("missing.ini").display()).as_slice()));
+        assert!(td.close().is_ok());
     }
 
-    pub fn get(&self, section: &str, option: &str) -> Result<String, FetchError> {
-        let mut expanded : HashSet<String> = HashSet::new();
-        self.get_interp(section, option, &mut expanded)
+    #[test]
+    fn test_read_dir() {
+        let td = new_tmp_dir().unwrap();
+        write_into(td.path(), "20-site.ini", "[app]\nport = 8080\nlevel = warn\n");
+        write_into(td.path(), "10-base.conf", "[app]\nport = 80\nname = base\n");
+        write_into(td.path(), "30-local.conf", "[app]\nlevel = debug\n");
+        write_into(td.path(), "40-ignored.txt", "[app]\nname = txt\n");
+        write_into(td.path(), ".50-hidden.conf", "[app]\nname = hidden\n");
+        let cp = ConfigParser::from_dir(td.path().as_str().unwrap(), &[]);
+        assert_eq!(cp.get("app", "port").unwrap(), "8080");
+        assert_eq!(cp.get("app", "level").unwrap(), "debug");
+        assert_eq!(cp.get("app", "name").unwrap(), "base");
+
+        let mut cp2 = ConfigParser::new(&[]);
+        let missing = td.path().join("missing");
+        assert!(cp2.read_dir(missing.as_str().unwrap()).is_ok());
+        assert!(cp2.sections().next().is_none());
+        assert_eq!(cp2.parse_errors()[0].kind(), ParseErrorKind::ReadError);
+        assert_eq!(cp2.parse_errors()[0].source(), format!("{}", missing.display()));
+        assert!(ConfigParser::try_from_dir(missing.as_str().unwrap(), &[]).is_err());
+        assert!(td.close().is_ok());
     }
 
-    // Now I wish Rust had default param values - having a boolean
-    // 'raw' would be handy here, to avoid the attempt to interpolate.
-    pub fn getboolean(&self, section: &str, option: &str) -> Result<bool, FetchError> {
-        let trues = vec!["true","yes","on","1",""];
-        let falses = vec!["false", "no", "off", "0"];
+    #[test]
+    fn test_include_dir() {
+        let td = new_tmp_dir().unwrap();
+        let conf_d = td.path().join("conf.d");
+        assert!(::std::old_io::fs::mkdir(&conf_d, ::std::old_io::USER_RWX).is_ok());
+        write_into(&conf_d, "b.conf", "[app]\nport = 8080\n");
+        write_into(&conf_d, "a.conf", "[app]\nport = 80\nname = a\n");
+        write_into(&conf_d, "c.ini", "[app]\nname = c\n");
+        let main = write_into(td.path(), "main.ini", "[app]\nname = main\n\
+                                                     include_dir = conf.d\n");
+        let mut cp = parser_with(&[], |o| o.allow_includes = true);
+        assert!(cp.read_file(main.as_str().unwrap()).is_ok());
+        assert_eq!(cp.get("app", "port").unwrap(), "8080");
+        assert_eq!(cp.get("app", "name").unwrap(), "c");
 
-        // note that empty string counts as true, ie, so we can
-        // have things like getboolean("foo", "skip_init") return
-        // true if we just have "skip_init" in config string,
-        // not necessarily "skip_init = true"
-        match self.get(section, option) {
-            Err(e) => Err(e),
-            Ok(v) => {
-                let lv = v.into_ascii_lowercase();
-                for &t in trues.iter() {
-                    if t == lv {
-                        return Ok(true)
-                    }
-                }
-                for &f in falses.iter() {
-                    if f == lv {
-                        return Ok(false)
-                    }
-                }
-                Err(fe_error(FetchErrorKind::InvalidLiteral))
+        let glob = write_into(td.path(), "glob.ini", "!include conf.d/?.conf\n");
+        let mut cp2 = parser_with(&[], |o| o.allow_includes = true);
+        assert!(cp2.read_file(glob.as_str().unwrap()).is_ok());
+        assert_eq!(cp2.get("app", "port").unwrap(), "8080");
+        assert_eq!(cp2.get("app", "name").unwrap(), "a");
+        assert!(cp2.parse_errors().is_empty());
+
+        // as is a directory which cannot be read
+        let bad = write_into(td.path(), "bad.ini", "[app]\n\ninclude_dir = none.d\nport = 1\n");
+        let mut cp3 = parser_with(&[], |o| o.allow_includes = true);
+        assert!(cp3.read_file(bad.as_str().unwrap()).is_ok());
+        assert_eq!(cp3.get("app", "port").unwrap(), "1");
+        let errs = cp3.parse_errors();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].kind(), ParseErrorKind::ReadError);
+        assert_eq!(errs[0].source(), format!("{}", bad.display()));
+        assert_eq!((errs[0].line(), errs[0].text()), (3, "include_dir = none.d".to_string()));
+        assert!(td.close().is_ok());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        use super::glob_match;
+        assert!(glob_match(b"*.conf", b"a.conf"));
+        assert!(glob_match(b"?-*.ini", b"1-web.ini"));
+        assert!(glob_match(b"a*b*c", b"abxbyc"));
+        assert!(glob_match(b"**", b""));
+        assert!(!glob_match(b"*.conf", b"a.ini"));
+        assert!(!glob_match(b"?", b""));
+        // many stars against a long name which nearly matches
+        let name : Vec<u8> = (0..5000).map(|_| b'a').collect();
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*b", &name[..]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let text = "[app]\nport = 80\n[unclosed\n  = nothing\n\n\
+                    # comment\nname = x\n[ ]\n";
+        match ConfigParser::try_from_str(text, &[]) {
+            Ok(_) => assert!(false),
+            Err(errs) => {
+                assert_eq!(errs.len(), 3);
+                assert_eq!(errs[0].kind(), ParseErrorKind::InvalidLine);
+                assert_eq!(errs[0].source(), "<string>");
+                assert_eq!((errs[0].line(), errs[0].column()), (3, 1));
+                assert_eq!(errs[0].text(), "[unclosed");
+                assert_eq!((errs[1].line(), errs[1].column()), (4, 3));
+                assert_eq!(errs[1].text(), "= nothing");
+                assert_eq!(errs[2].line(), 8);
+                assert_eq!(format!("{}", errs[0]),
+                           "<string>:3:1: Line is neither a section header nor an option: [unclosed");
             }
         }
-    }
+        // the rest is still read by the infallible constructor
+        let cp = ConfigParser::from_str(text, &[]);
+        assert_eq!(cp.get("app", "name").unwrap(), "x");
+        assert_eq!(cp.parse_errors().len(), 3);
+        assert!(ConfigParser::try_from_strs(&["[app]\nport = 80\n", "[b]\nc : d\n"], &[]).is_ok());
 
-    pub fn getuint(&self, section: &str, option: &str) -> Result<usize, FetchError> {
-        match self.get(section, option) {
-            Err(e) => Err(e),
-            Ok(v) => {
-                if v == "" {
-                    // empty string counts as a '1' value
-                    Ok(1)
-                } else {
-                    let m : Result<usize,ParseIntError> = FromStr::from_str(v.as_slice());
-                    match m {
-                        Ok(u) => Ok(u),
-                        Err(_) => Err(fe_error(FetchErrorKind::InvalidLiteral))
-                    }
-                }
+        let mut r = MemReader::new(b"[app]\nport = 80\n\xff\n".to_vec());
+        match ConfigParser::try_from_readers(&mut [ &mut r ], &[]) {
+            Ok(_) => assert!(false),
+            Err(errs) => {
+                assert_eq!(errs.len(), 1);
+                assert_eq!(errs[0].kind(), ParseErrorKind::ReadError);
+                assert_eq!(errs[0].source(), "<reader 1>");
+                assert_eq!(errs[0].line(), 3);
             }
         }
     }
 
-    pub fn getint(&self, section: &str, option: &str) -> Result<isize, FetchError> {
-        match self.get(section, option) {
-            Err(e) => Err(e),
-            Ok(v) => {
-                if v == "" {
-                    Ok(1)
-                } else {
-                    let m : Result<isize,ParseIntError> = FromStr::from_str(v.as_slice());
-                    match m {
-                        Ok(i) => Ok(i),
-                        Err(_) => Err(fe_error(FetchErrorKind::InvalidLiteral))
-                    }
-                }
-            }
+    #[test]
+    fn test_parse_errors_strict() {
+        let mut cp = parser_with(&[], |o| o.strict = true);
+        assert!(cp.read_str("[a]\nx = 1\n  x = 2\n").is_err());
+        let errs = cp.parse_errors();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].kind(), ParseErrorKind::Config(FetchErrorKind::DuplicateOption));
+        assert_eq!((errs[0].line(), errs[0].column()), (3, 3));
+        assert_eq!(errs[0].text(), "x = 2");
+
+        let td = new_tmp_dir().unwrap();
+        let a = write_into(td.path(), "a.ini", "[a]\nx = 1\n\ninclude = b.ini\n");
+        let b = write_into(td.path(), "b.ini", "!include a.ini\n");
+        let mut cp2 = parser_with(&[], |o| o.allow_includes = true);
+        assert!(cp2.read_file(a.as_str().unwrap()).is_err());
+        let errs = cp2.parse_errors();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].kind(), ParseErrorKind::Config(FetchErrorKind::IncludeCircularity));
+        assert_eq!(errs[0].source(), format!("{}", b.display()));
+        assert_eq!(errs[0].line(), 1);
+        assert!(td.close().is_ok());
+    }
+
+    #[test]
+    fn test_read_report() {
+        let td = new_tmp_dir().unwrap();
+        let sys = write_into(td.path(), "system.ini", "[app]\nport = 80\n");
+        let user = write_into(td.path(), "user.ini", "[app]\nport = 8080\n");
+        let missing = td.path().join("missing.ini");
+        let paths = [ sys.as_str().unwrap(), missing.as_str().unwrap(), user.as_str().unwrap() ];
+
+        let mut cp = ConfigParser::new(&[]);
+        let report = cp.read(&paths).unwrap();
+        assert_eq!(report.loaded, vec![sys.clone(), user.clone()]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, missing);
+        assert_eq!(report.failed[0].1.kind, IoErrorKind::FileNotFound);
+        assert_eq!(cp.get("app", "port").unwrap(), "8080");
+
+        let mut cp2 = ConfigParser::new(&[]);
+        match cp2.read_required(&paths) {
+            Ok(_) => assert!(false),
+            Err(e) => assert_eq!(e.kind(), FetchErrorKind::UnreadableFile)
         }
+        assert_eq!(cp2.get("app", "port").unwrap(), "80");
+        assert_eq!(cp2.parse_errors()[0].source(), format!("{}", missing.display()));
+        assert!(td.close().is_ok());
     }
 
-    pub fn getfloat(&self, section: &str, option: &str) -> Result<f64, FetchError> {
-        match self.get(section, option) {
-            Err(e) => Err(e),
-            Ok(v) => {
-                if v == "" {
-                    Ok(1.0f64)
-                } else {
-                    let m : Result<f64,ParseFloatError> = FromStr::from_str(v.as_slice());
-                    match m {
-                        Ok(i) => Ok(i),
-                        Err(_) => Err(fe_error(FetchErrorKind::InvalidLiteral))
-                    }
-                }
+    #[test]
+    fn test_origin() {
+        let mut opts = ParserOptions::new();
+        opts.explicit_inheritance = true;
+        let mut cp = ConfigParser::with_options(&[("retries", "3")], opts);
+        assert!(cp.read_strs(&["[DEFAULT]\nlevel = info\n[base]\nport = 80\n",
+                               "# site\n[web : base]\nport = 8080\n\
+                                debug = maybe\n"]).is_ok());
+        assert_eq!(cp.origin("web", "port").unwrap(), Origin::Source("<string>".to_string(), 3));
+        assert_eq!(cp.origin("base", "port").unwrap(), Origin::Source("<string>".to_string(), 4));
+        assert_eq!(cp.origin("web", "level").unwrap(), Origin::Source("<string>".to_string(), 2));
+        assert_eq!(cp.origin("web", "retries").unwrap(), Origin::Default);
+        cp.set("web", "port", "443");
+        assert_eq!(cp.origin("web", "port").unwrap(), Origin::Programmatic);
+        assert_eq!(cp.origin("web", "missing").unwrap_err().kind(), FetchErrorKind::NoSuchOption);
+        assert_eq!(cp.origin("nosuch", "port").unwrap_err().kind(), FetchErrorKind::NoSuchSection);
+
+        match cp.getboolean("web", "debug") {
+            Ok(_) => assert!(false),
+            Err(e) => {
+                assert_eq!(e.kind(), FetchErrorKind::InvalidLiteral);
+                assert_eq!(e.detail().unwrap(), "debug = maybe in [web], from <string> line 4");
             }
         }
-    }
 
-    pub fn sections(&self) -> Keys<String,Props> {
-        self.sections.keys()
+        let td = new_tmp_dir().unwrap();
+        let f = write_into(td.path(), "site.ini", "[web]\nport = 80\n");
+        assert!(cp.read_file(f.as_str().unwrap()).is_ok());
+        assert_eq!(cp.origin("web", "port").unwrap(), Origin::Source(format!("{}", f.display()), 2));
+        assert!(td.close().is_ok());
     }
 
-    pub fn options(&self, section: &str) -> Result<Iter<String,InterpString>, FetchError> {
-        match self.sections.get(section) {
-            Some(opts) =>  Ok(opts.iter()),
-            None=> Err(fe_error(FetchErrorKind::NoSuchSection))
+    #[test]
+    fn test_write() {
+        let cp = ConfigParser::from_str("foo = quux\n  [Zulu] \nfoo =  bar\n\
+                  a_quuxly = barly\n  [ Alpha ] ; alpha section\nfoo : wibble", &[]);
+
+        let mut w = Vec::new();
+        match cp.to_writer(&mut w) {
+            Ok(_) => {
+                let out = from_utf8(w.as_slice()).unwrap();
+                assert_eq!(out, "[Zulu]\nfoo : bar\na_quuxly : barly\n\n[Alpha]\nfoo : wibble\n\n")
+            },
+            Err(_) => assert!(false)
         }
     }
-}
 
-#[cfg(test)]
+    #[test]
+    fn test_write_sorted() {
+        let mut opts = ParserOptions::new();
+        opts.sorted_output = true;
+        let mut cp = ConfigParser::with_options(&[], opts);
+        assert!(cp.read_str("[Zulu]\nfoo = bar\na_quuxly = barly\n[Alpha]\nfoo : wibble").is_ok());
+        assert_eq!(cp.to_string().unwrap(),
+                   "[Alpha]\nfoo : wibble\n\n[Zulu]\na_quuxly : barly\nfoo : bar\n\n");
+    }
 
-mod test {
-    extern crate env_logger;
+    #[test]
+    fn test_lossless() {
+        let text = "; settings for the web tier\n\n\
+                    [web]\n\
+                    host=example.org   ; public name\n\
+                    # the port\n\
+                    port : 80\n\
+                    paths = /a \\\n        /b\n\n\
+                    [db]\n\
+                    user = app\n\
+                    # trailing comment";
+        let mut cp = parser_with(&[], |o| o.lossless = true);
+        assert!(cp.read_str(text).is_ok());
+        assert_eq!(cp.to_string().unwrap(), text);
+        assert_eq!(cp.get("web", "paths").unwrap(), "/a /b");
 
-    use conparse::*;
-    use std::old_io::{MemReader,IoErrorKind,TempDir,File,Open,ReadWrite,IoResult};
-    use std::str::from_utf8;
+        cp.set("web", "port", "8080");
+        cp.set("web", "host", "example.com");
+        assert!(cp.remove_option("web", "paths").is_ok());
+        cp.set("db", "pass", "secret");
+        assert!(cp.add_section("cache").is_ok());
+        cp.set("cache", "size", "10");
+        assert_eq!(cp.to_string().unwrap(),
+                   "; settings for the web tier\n\n\
+                    [web]\n\
+                    host=example.com\n\
+                    # the port\n\
+                    port : 8080\n\n\
+                    [db]\n\
+                    user = app\n\
+                    pass = secret\n\
+                    # trailing comment\n\n\
+                    [cache]\n\
+                    size = 10\n");
 
-    #[test]
-    fn check_default() {
-        env_logger::init().unwrap();
+        let mut cp2 = parser_with(&[], |o| o.lossless = true);
+        assert!(cp2.read_str("[a]\nx = 1\n# keep\n[b]\ny = 2\n").is_ok());
+        assert!(cp2.remove_section("a").is_ok());
+        assert_eq!(cp2.to_string().unwrap(), "# keep\n[b]\ny = 2\n");
+
+        // options kept from outside any section are edited as the
+        // default section's
+        let mut cp3 = parser_with(&[], |o| {
+            o.lossless = true;
+            o.global_options = GlobalOptions::Defaults;
+        });
+        assert!(cp3.read_str("user = fred\nname = Fred\n[core]\neditor = vi\n").is_ok());
+        assert!(cp3.remove_option("DEFAULT", "user").is_ok());
+        cp3.set("DEFAULT", "name", "Wilma");
+        assert_eq!(cp3.to_string().unwrap(), "name = Wilma\n[core]\neditor = vi\n");
+        assert_eq!(cp3.get("core", "name").unwrap(), "Wilma");
+
+        // the bytes of a UTF-8 file come back as they were
+        let bytes = b"\xef\xbb\xbf[a]\r\nx = 1\r\n";
+        let mut cp4 = parser_with(&[], |o| o.lossless = true);
+        let mut r = MemReader::new(bytes.to_vec());
+        assert!(cp4.read_readers(&mut [ &mut r ]).is_ok());
+        assert_eq!(cp4.to_string().unwrap().into_bytes(), bytes.to_vec());
+    }
 
-        let rp = ConfigParser::new(&[( "t1", "v1"), ("t2", "v2")]);
-        assert!(rp.defaults.contains_key("t1"));
-        assert_eq!(rp.defaults.get("t1").unwrap().as_slice(), "v1");
-        assert_eq!(rp.defaults.len(), 2)
+    #[test]
+    fn test_lossless_continuations() {
+        let text = "[motd]\nbanner = Welcome\n  to the\n\n  machine\nlast = done\n";
+        let mut cp = parser_with(&[], |o| { o.lossless = true; o.indent_continuation = true; });
+        assert!(cp.read_str(text).is_ok());
+        assert_eq!(cp.get("motd", "banner").unwrap(), "Welcome\nto the\n\nmachine");
+        assert_eq!(cp.to_string().unwrap(), text);
+        cp.set("motd", "banner", "Hi");
+        assert_eq!(cp.to_string().unwrap(), "[motd]\nbanner = Hi\nlast = done\n");
     }
 
     #[test]
-    fn set_option() {
-        let mut rp = ConfigParser::new(&[( "t1", "v1"), ("t2", "v2")]);
-        rp.set("global", "t1", "sv1");
-        assert_eq!(rp.get("global", "t1").ok().unwrap(), "sv1");
-        assert_eq!(rp.get("global", "t2").ok().unwrap(), "v2");
-        let mut r = rp.get("no-section", "t3");
-        assert!(r.is_err() && r.err().unwrap().kind() == FetchErrorKind::NoSuchSection);
-        r = rp.get("global", "t3");
-        assert!(r.is_err() && r.err().unwrap().kind() == FetchErrorKind::NoSuchOption);
+    fn test_events() {
+        let text = "; top\n[web : base]\nhost = w1 # main\n  and w2\n\n[broken\n[db]\n";
+        let mut r = MemReader::new(text.as_bytes().to_vec());
+        let mut opts = ParserOptions::new();
+        opts.indent_continuation = true;
+        opts.explicit_inheritance = true;
+        opts.inline_comment_prefixes = vec!["#".to_string()];
+        let mut events = Events::new(&mut r, &opts);
+        let mut raws = String::new();
+        let mut evs = vec![];
+        while let Some(ev) = events.next() {
+            evs.push(ev.unwrap());
+            raws.push_str(events.raw());
+        }
+        assert_eq!(raws, text);
+        assert_eq!(evs, vec![
+            Event::Comment{ text : Cow::Borrowed("; top"), line : 1 },
+            Event::SectionStart{ name : Cow::Borrowed("web"), parent : Some(Cow::Borrowed("base")),
+                                 line : 2 },
+            Event::Option{ key : Cow::Borrowed("host"), value : Cow::Borrowed("w1"),
+                           comment : Some(Cow::Borrowed("# main")), append : false,
+                           array : false, line : 3 },
+            Event::Continuation{ text : Cow::Borrowed("and w2"), line : 4 },
+            Event::Blank{ line : 5 },
+            Event::Invalid{ text : Cow::Borrowed("[broken"), line : 6, column : 1 },
+            Event::SectionStart{ name : Cow::Borrowed("db"), parent : None, line : 7 }]);
+        assert_eq!(events.line(), 7);
+
+        let mut r = MemReader::new(b"[a]\nx = 0123456789\n[b]\n".to_vec());
+        let mut opts = ParserOptions::new();
+        opts.limits.max_line_length = Some(8);
+        let evs : Vec<Result<Event, LineError>> = Events::new(&mut r, &opts).collect();
+        assert_eq!(evs.len(), 2);
+        match evs[1] {
+            Err(LineError::TooLong(ref d)) =>
+                assert_eq!(d.as_slice(), "physical line over 8 bytes"),
+            _ => assert!(false)
+        }
     }
 
     #[test]
-    fn read_strings() {
-        let tinput = "One \\\n\\\n     Two\n\n#comment \nThree\nFour";
-        let mut v = MemReader::new(tinput.as_bytes().to_vec());
-        assert!(! v.eof());
-        let br = v.read_continued_line();
-        assert_eq!(br.unwrap().as_slice().trim(), "One Two");
-        let br = v.read_continued_line();
-        assert_eq!(br.unwrap().as_slice().trim(), "");
-        let br = v.read_continued_line();
-        assert_eq!(br.unwrap().as_slice().trim(), "Three");
-        let br = v.read_continued_line();
-        assert_eq!(br.unwrap().as_slice().trim(), "Four");
-        let br = v.read_continued_line();
-        assert_eq!(br.err().unwrap().kind, IoErrorKind::EndOfFile);
+    fn test_config_ref() {
+        let text = "orphan = 1\n[DEFAULT]\nroot = /srv\n[web]\n; comment\n\
+                    dir = %(root)s/www\nname : long \\\n   name\nport=80\n\
+                    [remote   \"origin\"]\nurl = git://h/r\n[web]\nport = 8080\n\
+                    loop = %(loop2)s\nloop2 = %(loop)s\nbad = x%(nope)sy\n";
+        let defaults = [("debug", "no")];
+        let cr = ConfigRef::from_str(text, &defaults);
+        let cp = ConfigParser::from_str(text, &defaults);
+        assert_eq!(cr.sections(), vec!["web", "remote \"origin\""]);
+        for &(s, o) in [("web", "dir"), ("web", "name"), ("web", "port"), ("web", "debug"),
+                        ("web", "root"), ("DEFAULT", "root"), ("web", "orphan"),
+                        ("remote \"origin\"", "url"), ("web", "loop"), ("web", "bad"),
+                        ("nosuch", "dir"), ("nosuch", "root")].iter() {
+            assert_eq!(cr.get_raw(s, o).map(|v| v.to_string()), cp.get_raw(s, o));
+            assert_eq!(cr.get(s, o).map(|v| v.into_owned()), cp.get(s, o));
+            assert_eq!(cr.origin(s, o), cp.origin(s, o));
+        }
+        assert_eq!(cr.getuint("web", "port").unwrap(), 8080);
+        assert_eq!(cr.getboolean("web", "debug").unwrap(), false);
+        assert_eq!(cr.getint("web", "dir").err().unwrap().detail(),
+                   cp.getint("web", "dir").err().unwrap().detail());
 
+        // plain values are slices of the text
+        let v = cr.get_raw("web", "port").unwrap();
+        let start = text.as_ptr() as usize;
+        assert!(v.as_ptr() as usize > start && (v.as_ptr() as usize) < start + text.len());
+
+        let cp2 = cr.to_parser();
+        assert_eq!(cp2.to_string().unwrap(), cp.to_string().unwrap());
+        assert_eq!(cp2.origin("web", "port"), cp.origin("web", "port"));
     }
 
     #[test]
-    fn read_iterated_strings() {
-        let cp = ConfigParser::from_strs( &["foo = quux\n  [sec1] \nfoo =  bar",
-                                            "[sec2]\nfoo : wibble"], &[]);
-        assert!(cp.sections.contains_key("sec1"));
-        assert!(cp.sections.contains_key("sec2"));
-        let ocs = cp.sections.get("sec1");
-        assert!(ocs.is_some());
-        let cs = ocs.unwrap();
-        assert!(cs.contains_key("foo"));
-        let ocv = cs.get("foo");
-        assert!(ocv.is_some());
-        let cv = ocv.unwrap();
-        assert_eq!("bar", cv.get_raw().as_slice());
-        let ocv2 = cp.get("sec2", "foo");
-        assert!(ocv2.is_ok());
-        let cv2 = ocv2.unwrap();
-        assert_eq!("wibble", cv2.as_slice());
+    fn test_interpolation_single_pass() {
+        let cp = ConfigParser::from_str("[s]\npct = %\nname = v\n\
+                                         repeat = %(name)s-%( name )s-%(name)s\n\
+                                         odd = %(pct)s(name)s\nhalf = %(name\n", &[]);
+        assert_eq!(cp.get("s", "repeat").unwrap(), "v-v-v");
+        // substituted text is not itself interpolated
+        assert_eq!(cp.get("s", "odd").unwrap(), "%(name)s");
+        assert_eq!(cp.get("s", "half").unwrap(), "%(name");
+    }
+
+    fn limit_error(cp : &mut ConfigParser, text : &str) -> (ParseErrorKind, usize) {
+        match cp.read_str(text) {
+            Ok(_) => assert!(false),
+            Err(e) => assert_eq!(e.kind(), FetchErrorKind::LimitExceeded)
+        }
+        let errs = cp.parse_errors();
+        (errs[0].kind(), errs[0].line())
     }
 
     #[test]
-    fn read_sections() {
-        let cp = ConfigParser::from_str("foo = quux\n  [Zulu] \n\
-                 foo =  bar\n  [ Alpha ] \nfoo : wibble", &[]);
-        let mut ks : Vec<&String> = cp.sections().collect();
-        ks.sort();
+    fn test_read_limits() {
+        let lim = ParseErrorKind::Config(FetchErrorKind::LimitExceeded);
+        let mut l = Limits::new();
+        l.max_line_length = Some(12);
+        let mut cp = parser_with(&[], |o| o.limits = l.clone());
+        assert_eq!(limit_error(&mut cp, "[a]\nx = 1\ny = 0123456789\n"), (lim, 3));
+        assert_eq!(cp.get("a", "x").unwrap(), "1");
 
-        assert_eq!(ks, vec![&"Alpha", &"Zulu"]);
+        l.max_line_length = None;
+        l.max_logical_line_length = Some(20);
+        let mut cp = parser_with(&[], |o| o.limits = l.clone());
+        assert!(cp.read_str("[a]\nx = 0123\\\n    4567\n").is_ok());
+        assert_eq!(limit_error(&mut cp, "[b]\nx = 0123\\\n 4567\\\n 89ab\\\n cdef\\\n ghij\n"),
+                   (lim, 2));
+
+        l.max_logical_line_length = None;
+        l.max_sections = Some(2);
+        let mut cp = parser_with(&[], |o| o.limits = l.clone());
+        assert_eq!(limit_error(&mut cp, "[a]\n[DEFAULT]\n[b]\n[a]\n[c]\n"), (lim, 5));
+
+        l.max_sections = None;
+        l.max_options = Some(3);
+        let mut cp = parser_with(&[], |o| o.limits = l.clone());
+        assert_eq!(limit_error(&mut cp, "[a]\nx = 1\nx = 2\ny = 3\n[b]\nx = 4\nz = 5\n"),
+                   (lim, 7));
     }
 
     #[test]
-    fn read_options() {
-        let cp = ConfigParser::from_str(
-            "foo = quux\n  \
-             [Zulu] \n\
-             foo =  bar\n\
-             [ Alpha ] \n\
-             foo : wibble\n\
-             standalone\n\
-             \n\
-             bar = quux  ", &[]);
-        let os = cp.options("NotHere");
-        assert!(os.is_err() && os.err().unwrap().kind() == FetchErrorKind::NoSuchSection);
-        let os2 = cp.options("Alpha");
-        assert!(os2.is_ok());
-        assert!(cp.has_option("Alpha", "standalone").unwrap());
-        let mut opts : Vec<(&String,&InterpString)> = os2.unwrap().collect();
-        opts.sort_by(|&(k1,_),&(k2,_)| k1.cmp(k2));
+    fn test_append_limits() {
+        // appends grow an option where it is, and only so far
+        let lim = ParseErrorKind::Config(FetchErrorKind::LimitExceeded);
+        let mut text = "[s]\nx = start\n".to_string();
+        for _ in 0..20000 {
+            text.push_str("x += 0123456789\n");
+        }
+        let mut cp = parser_with(&[], |o| {
+            o.append_operator = true;
+            o.limits = Limits::untrusted();
+        });
+        assert_eq!(limit_error(&mut cp, text.as_slice()), (lim, 5960));
+        assert_eq!(cp.get("s", "x").unwrap().len(), 65532);
 
-        let ev = [("bar","quux"), ("foo","wibble"), ("standalone", "")];
-        assert_eq!(opts.len(), ev.len());
-        for (&(k1,v1),&(k2,v2)) in opts.iter().zip(ev.iter()) {
-            assert_eq!(k1.as_slice(),k2);
-            assert_eq!(v1.get_raw().as_slice(),v2);
+        let mut text = "[s]\n".to_string();
+        for _ in 0..2000 {
+            text.push_str("x[] = v\n");
         }
+        let mut cp = parser_with(&[], |o| {
+            o.multi_value = true;
+            o.limits = Limits::untrusted();
+        });
+        assert_eq!(limit_error(&mut cp, text.as_slice()), (lim, 1002));
+        assert_eq!(cp.get_all("s", "x").unwrap().len(), 1000);
     }
 
-    // utility function to create a temp directory
-    // which can be closed afterwards to zap it.
+    #[test]
+    fn test_config_ref_limits() {
+        // ConfigRef keeps to limits just as the parser does
+        let mut cases = vec![];
+        let mut l = Limits::new();
+        l.max_line_length = Some(12);
+        cases.push((l, "[a]\nx = 1\ny = 0123456789\n"));
+        let mut l = Limits::new();
+        l.max_logical_line_length = Some(20);
+        cases.push((l, "[b]\nx = 0123\\\n 4567\\\n 89ab\\\n cdef\\\n ghij\n"));
+        let mut l = Limits::new();
+        l.max_sections = Some(2);
+        cases.push((l, "[a]\n[DEFAULT]\n[b]\n[a]\n[c]\n"));
+        let mut l = Limits::new();
+        l.max_options = Some(3);
+        cases.push((l, "[a]\nx = 1\nx = 2\ny = 3\n[b]\nx = 4\nz = 5\n"));
+        for &(ref l, text) in cases.iter() {
+            let mut cp = parser_with(&[], |o| o.limits = l.clone());
+            let e = cp.read_str(text).err().unwrap();
+            let re = ConfigRef::with_limits(text, &[], l.clone()).err().unwrap();
+            assert_eq!(re.kind(), FetchErrorKind::LimitExceeded);
+            assert_eq!(re.detail(), e.detail());
+        }
 
-    fn new_tmp_dir() -> IoResult<Box<TempDir>> {
-        match TempDir::new("conparse") {
-            Ok(dir) => Ok(Box::new(dir)),
+        let mut l = Limits::new();
+        l.max_value_length = Some(4);
+        let cr = ConfigRef::with_limits("[s]\na = xxx\nb = %(a)s%(a)s\n", &[], l).unwrap();
+        assert_eq!(cr.get("s", "a").unwrap().as_slice(), "xxx");
+        assert_eq!(cr.get("s", "b").err().unwrap().kind(), FetchErrorKind::LimitExceeded);
+    }
+
+    #[test]
+    fn test_interpolation_limits() {
+        // each option doubles the length of the one after it
+        let mut text = "[s]\n".to_string();
+        for i in 0..24 {
+            text.push_str(format!("o{} = %(o{})s%(o{})s\n", i, i + 1, i + 1).as_slice());
+        }
+        text.push_str("o24 = x\nshallow = %(o22)s\n");
+        let mut l = Limits::new();
+        l.max_interpolation_depth = Some(5);
+        l.max_value_length = Some(4096);
+        let mut cp = parser_with(&[], move |o| o.limits = l);
+        assert!(cp.read_str(text.as_slice()).is_ok());
+        assert_eq!(cp.get("s", "shallow").unwrap(), "xxxx");
+        assert_eq!(cp.get("s", "o20").unwrap().len(), 16);
+        match cp.get("s", "o10") {
+            Ok(_) => assert!(false),
+            Err(e) => assert_eq!(e.kind(), FetchErrorKind::LimitExceeded)
+        }
+        cp.opts.limits.max_interpolation_depth = None;
+        match cp.get("s", "o0") {
+            Ok(_) => assert!(false),
             Err(e) => {
-                error!("Cannot create temporary directory: {}", e);
-                Err(e)
+                assert_eq!(e.kind(), FetchErrorKind::LimitExceeded);
+                assert_eq!(e.detail().unwrap(), "value of o11 over 4096 bytes");
             }
         }
+        // a value may use the same option twice without that being
+        // circular
+        assert!(cp.read_str("[d]\nroot = /r\nb = %(root)s/b\nc = %(root)s/c\n\
+                             both = %(b)s %(c)s\n").is_ok());
+        assert_eq!(cp.get("d", "both").unwrap(), "/r/b /r/c");
     }
 
-    // utility function to create a temporary directory and a file
-    // within it containing the contents of 's', and return the tuple
-    // of (TempDir,path-to-file). On closing the temp dir, the
-    // contents are deleted.
-    
-    fn write_file<'a>(s: &'a str, fname: &'a str) -> IoResult<(Box<TempDir>,Path)> {
-        let tmpdir = new_tmp_dir().unwrap();
-        let mut tmppath = Path::new(tmpdir.path());
-        tmppath.push(fname);
-        match File::open_mode(&tmppath, Open, ReadWrite) {
-            Ok(mut f) => {
-                match f.write_str(s) {
-                    Ok(_) => {Ok((tmpdir,tmppath))},
-                    Err(e) => {error!("Failed to write to file {} : {}", tmppath.display(), e);
-                               Err(e)
+    #[test]
+    fn test_shared_references() {
+        // every option refers to every option on the level below, so
+        // that a fetch which looked each reference up afresh would
+        // make 200^9 lookups
+        let mut text = "[s]\n".to_string();
+        for i in 0..10 {
+            for j in 0..200 {
+                text.push_str(format!("l{}_{} = ", i, j).as_slice());
+                if i < 9 {
+                    for k in 0..200 {
+                        text.push_str(format!("%(l{}_{})s", i + 1, k).as_slice());
                     }
                 }
-            },
-            Err(e) => {
-                error!("Unable to write to temporary file {} : {}", tmppath.display(), e);
-                Err(e)
+                text.push_str("\n");
             }
         }
+        let mut cp = parser_with(&[], |o| o.limits = Limits::untrusted());
+        assert!(cp.read_str(text.as_slice()).is_ok());
+        assert_eq!(cp.get("s", "l0_0").unwrap(), "");
+        let cr = ConfigRef::from_str(text.as_slice(), &[]);
+        assert_eq!(cr.get("s", "l0_0").unwrap().as_slice(), "");
     }
 
     #[test]
-    fn read_file() {
-        let rtp = write_file("foo = quux\n  [Zulu] \n\
-             foo =  bar\n  [ Alpha ] \nfoo : wibble", "test_rf.ini");
-        assert!(rtp.is_ok());
-        let (td,tp) = rtp.unwrap();
-        info!("Written config file to {}", tp.display());
-        let cp = ConfigParser::from_file(tp.as_str().unwrap(), &[]);
-        assert_eq!(cp.get("Zulu", "foo").unwrap(), "bar");
-        assert_eq!(cp.get("Alpha", "foo").unwrap(), "wibble");
-        assert!(td.close().is_ok());
+    fn test_crlf_and_bom() {
+        let text = "\u{feff}[a]\r\nx = 1\r\ny = long \\\r\n  value\r\n; note\r\n\
+                    [b]\r\nz = 2\r";
+        let cp = ConfigParser::from_str(text, &[]);
+        let cr = ConfigRef::from_str(text, &[]);
+        let ss : Vec<&String> = cp.sections().collect();
+        assert_eq!(ss, vec![&"a", &"b"]);
+        assert_eq!(cr.sections(), vec!["a", "b"]);
+        for &(s, o, v) in [("a", "x", "1"), ("a", "y", "long value"), ("b", "z", "2")].iter() {
+            assert_eq!(cp.get(s, o).unwrap(), v);
+            assert_eq!(cr.get_raw(s, o).unwrap(), v);
+        }
+        assert!(cp.parse_errors().is_empty());
+
+        let mut cp = parser_with(&[], |o| o.lossless = true);
+        assert!(cp.read_str(text).is_ok());
+        assert_eq!(cp.to_string().unwrap(), text);
+
+        // only the start of the text may have a byte order mark
+        let text = "\u{feff}; top\n[a]\nx = 1\n\u{feff}y = 2\n";
+        let mut r = MemReader::new(text.as_bytes().to_vec());
+        let opts = ParserOptions::new();
+        let first = Events::new(&mut r, &opts).next().unwrap().unwrap();
+        assert_eq!(first, Event::Comment{ text : Cow::Borrowed("; top"), line : 1 });
+        let cp = ConfigParser::from_str(text, &[]);
+        let cr = ConfigRef::from_str(text, &[]);
+        assert!(cp.get("a", "y").is_err());
+        assert_eq!(cp.get("a", "\u{feff}y").unwrap(), "2");
+        assert!(cr.get_raw("a", "y").is_err());
+        assert_eq!(cr.get_raw("a", "\u{feff}y").unwrap(), "2");
     }
 
     #[test]
-    fn test_write() {
-        let cp = ConfigParser::from_str("foo = quux\n  [Zulu] \nfoo =  bar\n\
-                  a_quuxly = barly\n  [ Alpha ] ; alpha section\nfoo : wibble", &[]);
+    fn test_encodings() {
+        let bytes = b"[caf\xe9]\nname = Jos\xe9\n";
+        let read = |enc : Encoding| {
+            let mut opts = ParserOptions::new();
+            opts.encoding = enc;
+            let mut cp = ConfigParser::with_options(&[], opts);
+            let mut r = MemReader::new(bytes.to_vec());
+            assert!(cp.read_readers(&mut [ &mut r ]).is_ok());
+            cp
+        };
+        let cp = read(Encoding::Latin1);
+        assert_eq!(cp.get("caf\u{e9}", "name").unwrap(), "Jos\u{e9}");
+        let cp = read(Encoding::Windows1252);
+        assert_eq!(cp.get("caf\u{e9}", "name").unwrap(), "Jos\u{e9}");
+        let mut opts = ParserOptions::new();
+        opts.encoding = Encoding::Windows1252;
+        let mut cp = ConfigParser::with_options(&[], opts);
+        let mut r = MemReader::new(b"[s]\nquote = \x93hi\x94 \x96 \x80\x81\n".to_vec());
+        assert!(cp.read_readers(&mut [ &mut r ]).is_ok());
+        assert_eq!(cp.get("s", "quote").unwrap(), "\u{201c}hi\u{201d} \u{2013} \u{20ac}\u{81}");
+        let cp = read(Encoding::Utf8Lossy);
+        assert_eq!(cp.get("caf\u{fffd}", "name").unwrap(), "Jos\u{fffd}");
+        let cp = read(Encoding::Utf8Strict);
+        assert_eq!(cp.sections().count(), 0);
+        assert_eq!(cp.parse_errors()[0].kind(), ParseErrorKind::ReadError);
 
-        let mut w = Vec::new();
-        match cp.to_writer(&mut w) {
-            Ok(_) => {
-                let out = from_utf8(w.as_slice()).unwrap();
-                assert_eq!(out, "[Alpha]\nfoo : wibble\n\n[Zulu]\na_quuxly : barly\nfoo : bar\n\n")
-            },
-            Err(_) => assert!(false)
-        }
+        // a byte order mark is dropped whatever the encoding, though
+        // lossless mode keeps it, writing all text back as UTF-8
+        let mut cp = parser_with(&[], |o| { o.encoding = Encoding::Latin1; o.lossless = true; });
+        let mut r = MemReader::new(b"\xef\xbb\xbf[caf\xe9]\nname = Jos\xe9\n".to_vec());
+        assert!(cp.read_readers(&mut [ &mut r ]).is_ok());
+        assert_eq!(cp.get("caf\u{e9}", "name").unwrap(), "Jos\u{e9}");
+        assert_eq!(cp.to_string().unwrap(), "\u{feff}[caf\u{e9}]\nname = Jos\u{e9}\n");
+
+        // strings are already text, whatever the encoding
+        let mut opts = ParserOptions::new();
+        opts.encoding = Encoding::Latin1;
+        let mut cp = ConfigParser::with_options(&[], opts);
+        assert!(cp.read_str("[caf\u{e9}]\nname = Jos\u{e9}\n").is_ok());
+        assert_eq!(cp.get("caf\u{e9}", "name").unwrap(), "Jos\u{e9}");
+    }
+
+    #[test]
+    fn test_insertion_order() {
+        let mut cp = ConfigParser::from_str("[web]\nport = 80\nhost = w1\n\
+                                             [db]\nuser = app\n[cache]\n\
+                                             [web]\nname = site\nport = 8080\n", &[]);
+        let ss : Vec<&String> = cp.sections().collect();
+        assert_eq!(ss, vec![&"web", &"db", &"cache"]);
+        let ks : Vec<&String> = cp.options("web").unwrap().map(|(k, _)| k).collect();
+        assert_eq!(ks, vec![&"port", &"host", &"name"]);
+
+        // new sections and options go at the end, replaced ones stay put
+        cp.set("db", "host", "d1");
+        cp.set("web", "port", "443");
+        assert!(cp.add_section("auth").is_ok());
+        assert!(cp.remove_section("cache").is_ok());
+        assert_eq!(cp.to_string().unwrap(),
+                   "[web]\nport : 443\nhost : w1\nname : site\n\n\
+                    [db]\nuser : app\nhost : d1\n\n[auth]\n\n");
     }
 
     #[test]
@@ -1208,11 +4923,517 @@ mod test {
                                          foo =  bar\n  [ Alpha ] \n`
                                          foo : wibble\n\nbar = quux  ", &[]);
         match cp.to_string() {
-            Ok(s) => assert_eq!(s, "[Alpha]\nbar : quux\nfoo : wibble\n\n[Zulu]\nfoo : bar\n\n"),
+            Ok(s) => assert_eq!(s, "[Zulu]\nfoo : bar\n\n[Alpha]\nfoo : wibble\nbar : quux\n\n"),
             Err(_) => assert!(false)
         }
     }
 
+    #[test]
+    fn test_inline_comments() {
+        let mut cp = parser_with(&[], |o| {
+            o.inline_comment_prefixes = vec![";".to_string(), "#".to_string()];
+        });
+        cp.read_str("[server]\n\
+                     port = 8080 ; legacy port\n\
+                     colour = #ff0000\n\
+                     url = http://example.org/#anchor # where to go\n\
+                     quoted = \"a ; b\" ; quoted prefix\n\
+                     escaped = 10 \\; 20\n\
+                     plain = no comment here\n");
+        assert_eq!(cp.get("server", "port").unwrap(), "8080");
+        assert_eq!(cp.get("server", "colour").unwrap(), "");
+        assert_eq!(cp.get("server", "url").unwrap(), "http://example.org/#anchor");
+        assert_eq!(cp.get("server", "quoted").unwrap(), "\"a ; b\"");
+        assert_eq!(cp.get("server", "escaped").unwrap(), "10 ; 20");
+        assert_eq!(cp.get("server", "plain").unwrap(), "no comment here");
+
+        // without any prefixes, the comment is part of the value
+        let cp2 = ConfigParser::from_str("[server]\nport = 8080 ; legacy port\n", &[]);
+        assert_eq!(cp2.get("server", "port").unwrap(), "8080 ; legacy port");
+    }
+
+    #[test]
+    fn test_write_inline_comments() {
+        let mut cp = parser_with(&[], |o| {
+            o.inline_comment_prefixes = vec![";".to_string(), "#".to_string()];
+        });
+        cp.read_str("[server]\nport = 8080 ; legacy port\nescaped = 10 \\; 20\n");
+        let out = cp.to_string().unwrap();
+        assert_eq!(out, "[server]\nport : 8080 ; legacy port\nescaped : 10 \\; 20\n\n");
+
+        let mut cp2 = parser_with(&[], |o| {
+            o.inline_comment_prefixes = vec![";".to_string(), "#".to_string()];
+        });
+        cp2.read_str(out.as_slice());
+        assert_eq!(cp2.get("server", "escaped").unwrap(), "10 ; 20");
+        assert_eq!(cp2.get("server", "port").unwrap(), "8080");
+
+        // an escaped quote starts no quoted part, written or read
+        cp2.set("server", "quote", "a\\\" ; b");
+        let out = cp2.to_string().unwrap();
+        assert!(out.contains("quote : a\\\" \\; b\n"));
+        let mut cp3 = parser_with(&[], |o| {
+            o.inline_comment_prefixes = vec![";".to_string(), "#".to_string()];
+        });
+        cp3.read_str(out.as_slice());
+        assert_eq!(cp3.get("server", "quote").unwrap(), "a\\\" ; b");
+    }
+
+    #[test]
+    fn test_indent_continuation() {
+        let mut cp = parser_with(&[], |o| o.indent_continuation = true);
+        cp.read_str("[motd]\n\
+                     banner = Welcome\n\
+                     \x20   to the\n\
+                     \n\
+                     \x20     machine\n\
+                     \n\
+                     next = one \\\n\
+                     \x20      line\n\
+                     \x20 indented = key\n\
+                     \x20    and value\n\
+                     last : done\n\
+                     \n");
+        assert_eq!(cp.get("motd", "banner").unwrap(), "Welcome\nto the\n\nmachine");
+        assert_eq!(cp.get("motd", "next").unwrap(), "one line\nindented = key\nand value");
+        assert_eq!(cp.get("motd", "last").unwrap(), "done");
+
+        // without the option, indented lines are options in their own right
+        let cp2 = ConfigParser::from_str("[motd]\nbanner = Welcome\n  to = the machine\n", &[]);
+        assert_eq!(cp2.get("motd", "banner").unwrap(), "Welcome");
+        assert_eq!(cp2.get("motd", "to").unwrap(), "the machine");
+    }
+
+    #[test]
+    fn test_write_multiline() {
+        let mut cp = parser_with(&[], |o| o.indent_continuation = true);
+        cp.set("motd", "banner", "Welcome\nto the\n\nmachine");
+        cp.set("motd", "single", "line");
+        let out = cp.to_string().unwrap();
+        assert_eq!(out, "[motd]\nbanner : Welcome\n    to the\n\n    machine\nsingle : line\n\n");
+
+        let mut cp2 = parser_with(&[], |o| o.indent_continuation = true);
+        cp2.read_str(out.as_slice());
+        assert_eq!(cp2.get("motd", "banner").unwrap(), "Welcome\nto the\n\nmachine");
+        assert_eq!(cp2.get("motd", "single").unwrap(), "line");
+
+        // values which would not read back are refused, unless quoted
+        cp.set("motd", "banner", "Welcome\n# to the machine");
+        match cp.to_string() {
+            Ok(_) => assert!(false),
+            Err(e) => assert_eq!(e.kind, IoErrorKind::InvalidInput)
+        }
+        let mut cp3 = ConfigParser::new(&[]);
+        cp3.set("motd", "banner", "Welcome\nto the machine");
+        assert!(cp3.to_string().is_err());
+        let mut cp4 = parser_with(&[], |o| o.quoted_values = true);
+        cp4.set("motd", "banner", "Welcome\n# to the machine");
+        let out = cp4.to_string().unwrap();
+        let mut cp5 = parser_with(&[], |o| o.quoted_values = true);
+        cp5.read_str(out.as_slice());
+        assert_eq!(cp5.get("motd", "banner").unwrap(), "Welcome\n# to the machine");
+    }
+
+    #[test]
+    fn test_quoted_values() {
+        let mut cp = parser_with(&[], |o| {
+            o.quoted_values = true;
+            o.inline_comment_prefixes = vec!["#".to_string()];
+        });
+        cp.read_str("[q]\n\
+                     greeting = \"  hello\\tworld\\n\"\n\
+                     hash = \"#1 # not a comment\" # a comment\n\
+                     path = \"C:\\\\temp\\\\\"\n\
+                     snowman = \"\\u2603 \\\"quoted\\\"\"\n\
+                     unterminated = \"oops\n\
+                     trailing = \"a\" b\n\
+                     plain = as is\n");
+        assert_eq!(cp.get("q", "greeting").unwrap(), "  hello\tworld\n");
+        assert_eq!(cp.get("q", "hash").unwrap(), "#1 # not a comment");
+        assert_eq!(cp.get("q", "path").unwrap(), "C:\\temp\\");
+        assert_eq!(cp.get("q", "snowman").unwrap(), "\u{2603} \"quoted\"");
+        assert_eq!(cp.get("q", "unterminated").unwrap(), "\"oops");
+        assert_eq!(cp.get("q", "trailing").unwrap(), "\"a\" b");
+        assert_eq!(cp.get("q", "plain").unwrap(), "as is");
+    }
+
+    #[test]
+    fn test_write_quoted_values() {
+        let mut cp = parser_with(&[], |o| {
+            o.quoted_values = true;
+            o.inline_comment_prefixes = vec!["#".to_string()];
+        });
+        let vals = [("a", "  padded  "), ("b", "two\nlines"), ("c", "ends in \\"),
+                    ("d", "\"starts quoted"), ("e", "has # hash"), ("f", "bell\x07"),
+                    ("g", "nothing special")];
+        for &(k, v) in vals.iter() {
+            cp.set("q", k, v);
+        }
+        let out = cp.to_string().unwrap();
+        assert_eq!(out, "[q]\n\
+                         a : \"  padded  \"\n\
+                         b : \"two\\nlines\"\n\
+                         c : \"ends in \\\\\"\n\
+                         d : \"\\\"starts quoted\"\n\
+                         e : \"has # hash\"\n\
+                         f : \"bell\\u0007\"\n\
+                         g : nothing special\n\n");
+        let mut cp2 = parser_with(&[], |o| {
+            o.quoted_values = true;
+            o.inline_comment_prefixes = vec!["#".to_string()];
+        });
+        cp2.read_str(out.as_slice());
+        for &(k, v) in vals.iter() {
+            assert_eq!(cp2.get("q", k).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn test_strict_duplicates() {
+        let mut cp = parser_with(&[], |o| o.strict = true);
+        match cp.read_str("[a]\nx = 1\n# comment\n\n[b]\ny = 2\n[a]\nz = 3\n") {
+            Ok(_) => assert!(false),
+            Err(e) => {
+                assert_eq!(e.kind(), FetchErrorKind::DuplicateSection);
+                assert_eq!(e.detail().unwrap(),
+                           "section [a] in <string> at line 7 (first seen at line 1)");
+            }
+        }
+
+        let mut cp = parser_with(&[], |o| o.strict = true);
+        match cp.read_str("[a]\nx = 1\ny = one \\\n  two\n\n  x : 3\n") {
+            Ok(_) => assert!(false),
+            Err(e) => {
+                assert_eq!(e.kind(), FetchErrorKind::DuplicateOption);
+                assert_eq!(e.detail().unwrap(),
+                           "option x of section [a] in <string> at line 6 (first seen at line 2)");
+            }
+        }
+        // the repeat is refused before it replaces the first value
+        assert_eq!(cp.get("a", "x").unwrap(), "1");
+
+        // the non-strict default just merges
+        let cp = ConfigParser::from_str("[a]\nx = 1\n[b]\n[a]\nx = 2\n", &[]);
+        assert_eq!(cp.get("a", "x").unwrap(), "2");
+    }
+
+    #[test]
+    fn test_strict_multiple_sources() {
+        let mut cp = parser_with(&[], |o| o.strict = true);
+        assert!(cp.read_strs(&["[a]\nx = 1\n", "[a]\nx = 2\n"]).is_ok());
+        assert_eq!(cp.get("a", "x").unwrap(), "2");
+
+        // each reader is named, so an error says which one it was in
+        let mut cp = parser_with(&[], |o| o.strict = true);
+        let mut r1 = MemReader::new(b"[a]\nx = 1\n".to_vec());
+        let mut r2 = MemReader::new(b"[a]\nx = 2\nx = 3\n".to_vec());
+        match cp.read_readers(&mut [ &mut r1, &mut r2 ]) {
+            Ok(_) => assert!(false),
+            Err(e) => {
+                assert_eq!(e.detail().unwrap(),
+                           "option x of section [a] in <reader 2> at line 3 (first seen at line 2)");
+            }
+        }
+        assert_eq!(cp.origin("a", "x").unwrap(), Origin::Source("<reader 2>".to_string(), 2));
+
+        // appends, and repeats of a multi-valued option, are not duplicates
+        let mut cp = parser_with(&[], |o| { o.strict = true; o.append_operator = true; });
+        assert!(cp.read_str("[a]\nx = 1\nx += 2\n").is_ok());
+        assert_eq!(cp.get("a", "x").unwrap(), "1 2");
+        let mut cp = parser_with(&[], |o| { o.strict = true; o.multi_value = true; });
+        assert!(cp.read_str("[a]\nx = 1\nx = 2\n").is_ok());
+        assert_eq!(cp.get_all("a", "x").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_global_options() {
+        let text = "user = fred\nname = Fred Bloggs\n[core]\neditor = vi\n";
+
+        let mut opts = ParserOptions::new();
+        opts.global_options = GlobalOptions::Section("global".to_string());
+        let mut cp = ConfigParser::with_options(&[], opts);
+        assert!(cp.read_str(text).is_ok());
+        assert_eq!(cp.get("global", "user").unwrap(), "fred");
+        assert_eq!(cp.get("global", "name").unwrap(), "Fred Bloggs");
+        assert!(! cp.has_option("core", "user").unwrap());
+
+        let mut opts = ParserOptions::new();
+        opts.global_options = GlobalOptions::Defaults;
+        let mut cp = ConfigParser::with_options(&[], opts);
+        assert!(cp.read_str(text).is_ok());
+        assert!(! cp.has_section("global"));
+        assert_eq!(cp.get("core", "user").unwrap(), "fred");
+        assert_eq!(cp.get("core", "editor").unwrap(), "vi");
+        assert_eq!(cp.origin("core", "user").unwrap(), Origin::Source("<string>".to_string(), 1));
+        assert_eq!(cp.origin("core", "name").unwrap(), Origin::Source("<string>".to_string(), 2));
+
+        // the global section is only made when needed, and by
+        // default such options are discarded
+        let mut opts = ParserOptions::new();
+        opts.global_options = GlobalOptions::Section("global".to_string());
+        let mut cp = ConfigParser::with_options(&[], opts);
+        assert!(cp.read_str("[core]\neditor = vi\n").is_ok());
+        assert!(! cp.has_section("global"));
+        let cp = ConfigParser::from_str(text, &[]);
+        assert!(cp.get("core", "user").is_err());
+    }
+
+    #[test]
+    fn test_default_section() {
+        let cp = ConfigParser::from_str("[DEFAULT]\n\
+                                         host = localhost\n\
+                                         url = http://%(host)s:%(port)s/\n\
+                                         [web]\n\
+                                         port = 80\n\
+                                         [admin]\n\
+                                         host = admin.local\n\
+                                         port = 8080\n\
+                                         link = see %(url)s\n", &[("port", "1")]);
+        let ks : Vec<&String> = cp.sections().collect();
+        assert_eq!(ks.len(), 2);
+        assert!(! cp.has_section("DEFAULT"));
+        assert!(cp.has_option("web", "url").unwrap());
+        assert_eq!(cp.get_raw("web", "host").unwrap(), "localhost");
+        assert_eq!(cp.get("web", "url").unwrap(), "http://localhost:80/");
+        assert_eq!(cp.get("admin", "url").unwrap(), "http://admin.local:8080/");
+        assert_eq!(cp.get("admin", "link").unwrap(), "see http://admin.local:8080/");
+        assert_eq!(cp.get("nowhere", "url").unwrap(), "http://localhost:1/");
+
+        let out = cp.to_string().unwrap();
+        assert!(out.starts_with("[DEFAULT]\nhost : localhost\nurl : http://%(host)s:%(port)s/\n\n\
+                                 [web]\n"));
+    }
+
+    #[test]
+    fn test_default_section_name() {
+        let text = "[common]\nuser = root\n[DEFAULT]\ngroup = wheel\n[box]\n";
+
+        let mut opts = ParserOptions::new();
+        opts.default_section = Some("common".to_string());
+        let mut cp = ConfigParser::with_options(&[], opts);
+        assert!(cp.read_str(text).is_ok());
+        assert_eq!(cp.get("box", "user").unwrap(), "root");
+        assert!(cp.get("box", "group").is_err());
+        assert!(cp.has_section("DEFAULT"));
+
+        let mut opts = ParserOptions::new();
+        opts.default_section = None;
+        let mut cp = ConfigParser::with_options(&[], opts);
+        assert!(cp.read_str(text).is_ok());
+        assert!(cp.get("box", "group").is_err());
+        assert!(cp.has_section("DEFAULT"));
+
+        // options kept from outside any section, with no default
+        // section to write them under, go back before the first header
+        let opts = {
+            let mut o = ParserOptions::new();
+            o.default_section = None;
+            o.global_options = GlobalOptions::Defaults;
+            o
+        };
+        let mut cp = ConfigParser::with_options(&[], opts.clone());
+        assert!(cp.read_str("user = fred\n[box]\nname = b\n").is_ok());
+        let out = cp.to_string().unwrap();
+        assert_eq!(out, "user : fred\n\n[box]\nname : b\n\n");
+        let mut cp2 = ConfigParser::with_options(&[], opts);
+        assert!(cp2.read_str(out.as_slice()).is_ok());
+        assert_eq!(cp2.get("box", "user").unwrap(), "fred");
+        assert_eq!(cp2.get("box", "name").unwrap(), "b");
+    }
+
+    #[test]
+    fn test_subsections() {
+        let cp = ConfigParser::from_str("[core]\nbare = false\n\
+                                         [remote \"origin\"]\nurl = git://a/b\n\
+                                         [remote   \"we\\\"ird\\\\\"]\nurl = git://c/d\n\
+                                         [branch \"main\"]\nremote = origin\n", &[]);
+        assert_eq!(cp.subsections("remote"), vec!["origin".to_string(), "we\"ird\\".to_string()]);
+        assert_eq!(cp.subsections("branch"), vec!["main".to_string()]);
+        assert!(cp.subsections("core").is_empty());
+
+        let weird = ConfigParser::section_key("remote", Some("we\"ird\\"));
+        assert_eq!(weird, "remote \"we\\\"ird\\\\\"");
+        assert_eq!(cp.get(weird.as_slice(), "url").unwrap(), "git://c/d");
+        assert_eq!(ConfigParser::split_section_key(weird.as_slice()),
+                   ("remote".to_string(), Some("we\"ird\\".to_string())));
+        assert_eq!(ConfigParser::split_section_key("My Service"),
+                   ("My Service".to_string(), None));
+
+        let out = cp.to_string().unwrap();
+        assert!(out.contains("[remote \"we\\\"ird\\\\\"]\nurl : git://c/d\n"));
+        let cp2 = ConfigParser::from_str(out.as_slice(), &[]);
+        assert_eq!(cp2.subsections("remote"), cp.subsections("remote"));
+        assert_eq!(cp2.get(weird.as_slice(), "url").unwrap(), "git://c/d");
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let mut cp = parser_with(&[("timeout", "30")], |o| {
+            o.explicit_inheritance = true;
+            o.dotted_inheritance = true;
+        });
+        assert!(cp.read_str("[db_base]\n\
+                             user = app\n\
+                             port = 5432\n\
+                             dsn = %(host)s:%(port)s\n\
+                             [db_primary : db_base]\n\
+                             host = db1\n\
+                             [db_replica : db_primary]\n\
+                             host = db2\n\
+                             [db]\n\
+                             pool = 10\n\
+                             [db.reports.eu]\n\
+                             host = db3\n").is_ok());
+        assert_eq!(cp.parent("db_replica"), Some("db_primary".to_string()));
+        assert!(cp.has_section("db_replica"));
+        assert!(cp.has_option("db_replica", "user").unwrap());
+        assert_eq!(cp.get_raw("db_replica", "dsn").unwrap(), "%(host)s:%(port)s");
+        assert_eq!(cp.get("db_primary", "dsn").unwrap(), "db1:5432");
+        assert_eq!(cp.get("db_replica", "dsn").unwrap(), "db2:5432");
+        assert_eq!(cp.get("db_replica", "timeout").unwrap(), "30");
+        assert_eq!(cp.get("db.reports.eu", "pool").unwrap(), "10");
+        assert_eq!(cp.get("db.reports.eu", "host").unwrap(), "db3");
+        match cp.get("db_base", "host") {
+            Ok(_) => assert!(false),
+            Err(e) => assert_eq!(e.kind(), FetchErrorKind::NoSuchOption)
+        }
+
+        let out = cp.to_string().unwrap();
+        assert!(out.contains("[db_replica : db_primary]\nhost : db2\n"));
+        let mut cp2 = parser_with(&[("timeout", "30")], |o| {
+            o.explicit_inheritance = true;
+            o.dotted_inheritance = true;
+        });
+        assert!(cp2.read_str(out.as_slice()).is_ok());
+        assert_eq!(cp2.get("db_replica", "dsn").unwrap(), "db2:5432");
+    }
+
+    #[test]
+    fn test_inheritance_cycle() {
+        let mut cp = parser_with(&[("timeout", "30")], |o| {
+            o.explicit_inheritance = true;
+            o.dotted_inheritance = true;
+        });
+        assert!(cp.read_str("[a : b]\nx = 1\n[b : c]\n[c : a]\n").is_ok());
+        assert_eq!(cp.get("a", "x").unwrap(), "1");
+        match cp.get("a", "y") {
+            Ok(_) => assert!(false),
+            Err(e) => {
+                assert_eq!(e.kind(), FetchErrorKind::InheritanceCircularity);
+                assert_eq!(e.detail().unwrap(), "a -> b -> c -> a");
+            }
+        }
+        cp.set_parent("c", None);
+        assert!(cp.get("a", "y").is_err());
+        assert_eq!(cp.get("c", "x").unwrap_or("none".to_string()), "none");
+        assert_eq!(cp.get("b", "x").unwrap_or("none".to_string()), "none");
+        assert_eq!(cp.has_option("a", "timeout").unwrap(), true);
+    }
+
+    #[test]
+    fn test_multi_value() {
+        let text = "[lb]\n\
+                    domain = example.org\n\
+                    server = a.%(domain)s\n\
+                    server = b.%(domain)s\n\
+                    backup[] = c\n\
+                    backup [] = d\n\
+                    port = 80\n";
+        let mut cp = parser_with(&[], |o| o.multi_value = true);
+        assert!(cp.read_str(text).is_ok());
+        assert_eq!(cp.get_all("lb", "server").unwrap(),
+                   vec!["a.example.org".to_string(), "b.example.org".to_string()]);
+        assert_eq!(cp.get("lb", "server").unwrap(), "b.example.org");
+        assert_eq!(cp.get_all("lb", "backup").unwrap(), vec!["c".to_string(), "d".to_string()]);
+        assert_eq!(cp.get_all("lb", "port").unwrap(), vec!["80".to_string()]);
+        assert!(cp.get_all("lb", "missing").is_err());
+
+        // normally the last one wins, and [] is part of the key
+        let cp2 = ConfigParser::from_str(text, &[]);
+        assert_eq!(cp2.get_all("lb", "server").unwrap(), vec!["b.example.org".to_string()]);
+        assert_eq!(cp2.get("lb", "backup[]").unwrap(), "c");
+        assert_eq!(cp2.get("lb", "backup []").unwrap(), "d");
+    }
+
+    #[test]
+    fn test_write_multi_value() {
+        let mut cp = parser_with(&[], |o| o.multi_value = true);
+        assert!(cp.read_str("[lb]\nserver = a\nserver[] = b\nport = 80\n").is_ok());
+        let out = cp.to_string().unwrap();
+        assert_eq!(out, "[lb]\nserver : a\nserver : b\nport : 80\n\n");
+        let mut cp2 = parser_with(&[], |o| o.multi_value = true);
+        assert!(cp2.read_str(out.as_slice()).is_ok());
+        assert_eq!(cp2.get_all("lb", "server").unwrap(), vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn test_append_operator() {
+        let base = "[unit]\n\
+                    after = network.target\n\
+                    opts = fast\n\
+                    [service : unit]\n\
+                    name = web\n";
+        let mut cp = parser_with(&[("flags", "-v")], |o| {
+            o.append_operator = true;
+            o.explicit_inheritance = true;
+            o.strict = true;
+        });
+        assert!(cp.read_strs(&[base, "[unit]\n\
+                                       after += time-sync.target\n\
+                                       after += local-fs.target\n\
+                                       [service]\n\
+                                       opts += safe\n\
+                                       flags += -q\n\
+                                       name += app\n"]).is_ok());
+        assert_eq!(cp.get("unit", "after").unwrap(),
+                   "network.target time-sync.target local-fs.target");
+        // appends to inherited values and defaults stay in the section
+        assert_eq!(cp.get("service", "opts").unwrap(), "fast safe");
+        assert_eq!(cp.get("unit", "opts").unwrap(), "fast");
+        assert_eq!(cp.get("service", "flags").unwrap(), "-v -q");
+        assert_eq!(cp.get("unit", "flags").unwrap(), "-v");
+        assert_eq!(cp.get("service", "name").unwrap(), "web app");
+        // an empty assignment is just empty without multi_value
+        assert!(cp.read_str("[unit]\nafter =\n").is_ok());
+        assert_eq!(cp.get("unit", "after").unwrap(), "");
+    }
+
+    #[test]
+    fn test_append_multi_value() {
+        let mut cp = parser_with(&[("flags", "-v")], |o| {
+            o.append_operator = true;
+            o.multi_value = true;
+            o.explicit_inheritance = true;
+            o.strict = true;
+        });
+        assert!(cp.read_strs(&["[unit]\nafter = network.target\n",
+                               "[unit]\nafter += time-sync.target\n\
+                                [service : unit]\nafter += web.target\n"]).is_ok());
+        assert_eq!(cp.get_all("unit", "after").unwrap(),
+                   vec!["network.target".to_string(), "time-sync.target".to_string()]);
+        assert_eq!(cp.get_all("service", "after").unwrap(),
+                   vec!["network.target".to_string(), "time-sync.target".to_string(),
+                        "web.target".to_string()]);
+        // an empty assignment resets the list
+        assert!(cp.read_str("[unit]\nafter =\n").is_ok());
+        assert_eq!(cp.get_all("unit", "after").unwrap(), vec![]);
+        assert_eq!(cp.get("unit", "after").unwrap(), "");
+        assert!(cp.read_str("[unit]\nafter =\nafter = local-fs.target\n").is_ok());
+        assert_eq!(cp.get_all("unit", "after").unwrap(), vec!["local-fs.target".to_string()]);
+
+        // a cleared option survives writing and reading back
+        assert!(cp.read_str("[service]\nafter =\n").is_ok());
+        let mut cp2 = parser_with(&[("flags", "-v")], |o| {
+            o.append_operator = true;
+            o.multi_value = true;
+            o.explicit_inheritance = true;
+            o.strict = true;
+        });
+        assert!(cp2.read_str(cp.to_string().unwrap().as_slice()).is_ok());
+        assert_eq!(cp2.get_all("service", "after").unwrap(), vec![]);
+        assert_eq!(cp2.get_all("unit", "after").unwrap(), vec!["local-fs.target".to_string()]);
+    }
+
     #[test]
     fn test_null_interp() {
         let cp = ConfigParser::from_str("foo = quux\n  [Zulu] \nfoo =  bar\n\
@@ -1254,6 +5475,24 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_odd_option_keys() {
+        let cp = ConfigParser::from_str("[global]\n\
+              max-connections = 100\n\
+              log.level : DEBUG\n\
+              Proxy Host  =  proxy.example.org\n\
+              [broken = yes\n\
+              url = http://%( Proxy Host )s:8080/%(log.level)s/%(max-connections)s\n", &[]);
+        assert_eq!(cp.get("global", "max-connections").unwrap(), "100");
+        assert_eq!(cp.get("global", "log.level").unwrap(), "DEBUG");
+        assert_eq!(cp.get("global", "Proxy Host").unwrap(), "proxy.example.org");
+        assert!(! cp.has_option("global", "[broken").unwrap());
+        match cp.get("global", "url") {
+            Ok(v) => assert_eq!(v, "http://proxy.example.org:8080/DEBUG/100"),
+            Err(_) => assert!(false)
+        }
+    }
+
     #[test]
     fn test_bad_interp() {
         let cp = ConfigParser::from_str("[Section1]\nfoo =  My %(nofrob)s\nfrob : Option\n", &[]);
@@ -1,7 +1,5 @@
 extern crate posix;
-extern crate regex;
 
-use self::regex::Regex;
 use std::old_io::{IoResult,IoErrorKind,IoError};
 // only need env for non-test builds
 #[cfg(not(test))]
@@ -224,6 +222,25 @@ pub fn get_homedir(uname : &str) -> String {
     }
 }
 
+// the user name (empty for the current user) and the remainder of a
+// path of the form `~<username>/remainder`, if it is one
+#[cfg(unix)]
+fn split_tilde(ps : &str) -> Option<(&str, &str)> {
+    let t = ps.trim_left();
+    if ! t.starts_with("~") {
+        return None
+    }
+    let t = &t[1..];
+    let end = match t.find(|c: char| ! (c.is_alphanumeric() || c == '_')) {
+        Some(i) => i,
+        None => t.len()
+    };
+    if ! t[end..].starts_with("/") || t[end+1..].contains("\n") {
+        return None
+    }
+    Some((&t[..end], &t[end+1..]))
+}
+
 /// Equivalent to python os.expanduser(), to expand a path of
 /// the form `~<username>/path/to/file` into the full absolute
 /// file system path. Only defined for posix style systems.
@@ -239,13 +256,6 @@ pub fn get_homedir(uname : &str) -> String {
 /// ```
 #[cfg(unix)]
 pub fn expand_homedir(p : &Path) -> IoResult<Path> {
-    let u_re = match Regex::new(r"^\s*~(\w*)/(.*)$") {
-        Err(_) => return Err(IoError { kind : IoErrorKind::OtherIoError,
-                                       desc : "Regular expression for homedir does not compile",
-                                       detail : None}),
-        Ok(r) => r
-    };
-    
     let ps = match p.as_str() {
         Some(s) => s,
         None => ""
@@ -257,43 +267,21 @@ pub fn expand_homedir(p : &Path) -> IoResult<Path> {
                              detail : None})   
     }
     
-    match u_re.captures(ps) {
-        Some(c) => {
-            match c.at(1) {
-                Some(u) => {
-                    let mut rp = match u {
-                        
-                        "" =>  match fetch_home_dir() {
-                            // Just a "~/" string, so no need
-                            // for getpwnam, can just ask for home dir
-
-                            Some(h) => Path::new(h),
-                            None => Path::new("/") // no home dir -
-                                // assume root
-                        },
-                        uname => Path::new(get_homedir(uname))
-                    };
-                    
-                    match c.at(2) {
-                        Some(rem) => {
-                            rp.push(rem);
-                            Ok(rp.clone())
-                        },
-                        None => {
-                            warn!("Cannot get second capture group from regex match");
-                            Err(IoError { kind : IoErrorKind::OtherIoError,
-                                          desc : "Regular expression path capture failed",
-                                          detail : None})
-                        }
-                    }
+    match split_tilde(ps) {
+        Some((u, rem)) => {
+            let mut rp = match u {
+                "" =>  match fetch_home_dir() {
+                    // Just a "~/" string, so no need
+                    // for getpwnam, can just ask for home dir
+
+                    Some(h) => Path::new(h),
+                    None => Path::new("/") // no home dir -
+                        // assume root
                 },
-                None => {
-                    warn!("Unable to fetch username from capture group");
-                    Err(IoError { kind : IoErrorKind::OtherIoError,
-                                  desc : "Regular expression username capture failed",
-                                  detail : None})
-                }
-            }
+                uname => Path::new(get_homedir(uname))
+            };
+            rp.push(rem);
+            Ok(rp)
         },
         None => Ok(p.clone()) // no home dir to expand
     }
@@ -13,23 +13,45 @@
 //! to read that configuration.
 //!
 //! The text which can be read can include comments (which are lines
-//! beginning with a `;` or `#` character), and can also support Rust
+//! whose first non-blank character is a `;` or `#`), and can also support Rust
 //! style continuations. A line ending with a `\` character does not
 //! cause the line to be consumed immediately, but prepended to the
 //! next line read, and so on, until a line not ending with a `\`
 //! character is found, or the end of the file is reached. Note that
 //! leading whitespace is ignored on continued lines, so one can make
 //! the configuration file visually appealing without changing its
-//! meaning.
+//! meaning. A comment line ends a continuation, unless the
+//! `comments_in_continuations` option (see below) is set.
+//!
+//! Python's style of continuation, where lines indented further than
+//! the option line are joined onto its value with newlines, is also
+//! available with the `indent_continuation` option. Values containing
+//! newlines are written out in this indented form, or quoted with the
+//! `quoted_values` option. Writing fails with an `InvalidInput` error
+//! rather than write a value which would not read back as it is, such
+//! as one with newlines but neither option, or one written unquoted
+//! with a continuation line starting with `#` or `;`.
 //!
 //! The files are divided up into sections, which are started with the
-//! text `[ Section_Name ]`. The section line can also have a comment
-//! after its `]` character.
-//!
-//! Options have the form of `key : value`, where key is an identifier
-//! like string (typically `A-Z`, `a-z`, `0-9`, `_`) and value is a generic
-//! string. The alternative form of `key = value` is allowed. Leading
-//! whitespace after the `=` or `:` characters is stripped.
+//! text `[ Section_Name ]`. As with Python, a section name may contain
+//! any character other than `]` (so `[server.eu-west]` and `[My
+//! Service]` are both fine); whitespace surrounding the name is
+//! ignored. The section line can also have a comment after its `]`
+//! character.
+//!
+//! git-config style headers such as `[remote "origin"]`, which give a
+//! section name and a quoted subsection, are also understood. Such a
+//! section is addressed by the key which `ConfigParser::section_key`
+//! builds from the name and subsection (this is simply the header
+//! text in a canonical form), and `subsections` lists the subsections
+//! belonging to a given name.
+//!
+//! Options have the form of `key : value`, where key is any text up to
+//! the first `:` or `=` character (so `max-connections`, `log.level`
+//! and `Proxy Host` are all valid keys) and value is a generic
+//! string. The alternative form of `key = value` is allowed. Whitespace
+//! around the key, and leading whitespace after the `=` or `:`
+//! characters, is stripped.
 //!
 //! ConfigParser also supports value interpolation (like its Python
 //! inspired counterpart), so that strings which have the form
@@ -43,7 +65,11 @@
 //! Lastly, the application initialising a ConfigParser object can
 //! supply a set of default (key, value) pairs which will be supplied
 //! as values even if the configuration files do not contain those
-//! values.
+//! values. Configuration files can also supply defaults of their own
+//! in a `[DEFAULT]` section, just as in Python: options there are
+//! used by every section which does not set them itself (being
+//! interpolated in that section's context), take precedence over the
+//! application supplied defaults, and are not listed by `sections()`.
 //!
 //! ## An example configuration file
 //!
@@ -112,14 +138,34 @@
 //! | NoSuchOption | The requested option cannot be found |
 //! | InterpolationError | An option was found, but requested an interpolation object which cannot be found |
 //! | InterpolationCircularity | The requested interpolation caused a recursive loop |
+//! | InheritanceCircularity | A section inherits, directly or indirectly, from itself |
+//! | IncludeCircularity | A file includes, directly or indirectly, itself |
+//! | IncludeDepthExceeded | Included files are nested more deeply than `max_include_depth` allows |
+//! | UnreadableFile | A file passed to `read_required` cannot be opened |
 //! | DuplicateSection | An attempt was made to insert a new section which already exists |
+//! | DuplicateOption | An option was repeated within one section of a source (only in strict mode) |
 //! | InvalidLiteral | A typed option coerce failed because the text did not contain an object of that type |
+//! | LimitExceeded | Text read, or a value built by appending or interpolation, went over one of the parser's `Limits` |
 //!
 //! That last error is caused when using the convenience methods
 //! `getuint`, `getboolean` etc, and is emitted when attempting to coerce
 //! an invalidly formed string value (e.g. `frob`) into a boolean,
 //! integer or float value.
 //!
+//! Problems found while reading configuration text are recorded as
+//! `ParseError`s, giving the source, line, column and text of each
+//! malformed line or unreadable source. The `from_` constructors
+//! skip over such problems, but their `try_from_` counterparts
+//! (`try_from_str`, `try_from_files` and so on) return either the
+//! parser or every `ParseError` found, and `parse_errors` lists those
+//! seen by any parser.
+//!
+//! When a layered configuration gives a surprising value, `origin`
+//! tells where that value was set: at a line of a file or string,
+//! programmatically with `set`, or as a constructor default. The
+//! same information is added to the detail of `InvalidLiteral`
+//! errors.
+//!
 //! Options can be fetched in a raw string format (ie, where no
 //! interpolation is attempted) by using the `get_raw` method.
 //!
@@ -157,6 +203,92 @@
 //! The values will be placed in order of configuration source, with
 //! keys from `~/.myapprc` replacing those from `config.txt`.
 //!
+//! To find out which files were actually loaded, read them with
+//! `read`, whose `ReadReport` lists the paths loaded and those which
+//! could not be opened, with the reason. `read_required` instead
+//! fails at the first file which cannot be opened.
+//!
+//! With the `allow_includes` option a file can also pull in others
+//! itself, with an `include = path` or `!include path` line. Paths
+//! may start with `~` or `~user`, and relative paths are found
+//! relative to the including file. Circular includes, or includes
+//! nested beyond `max_include_depth`, stop loading with an error.
+//! An `include` path may end in a `*` or `?` pattern, such as
+//! `conf.d/*.conf`, and `include_dir = path` reads a whole directory.
+//! A file or directory which cannot be read is recorded as a
+//! `ReadError` of the include line (see `parse_errors`), and loading
+//! carries on.
+//!
+//! A directory of fragments, in the style of `/etc/sysctl.d`, can be
+//! read with `from_dir` or `read_dir`. Its `*.conf` and `*.ini` files
+//! are read in order of their names, so that `20-site.conf` overrides
+//! `10-base.conf`.
+//!
+//! ## Parser Options
+//!
+//! Behaviour which differs from the defaults described above is
+//! selected with a `ParserOptions` structure, handed to
+//! `ConfigParser::with_options`. The configuration is then loaded
+//! with the `read_str`, `read_strs`, `read_files`, `read_file` or
+//! `read_readers` methods, which behave just like their `from_`
+//! counterparts, except that they return a `Result` so that problems
+//! such as duplicates in `strict` mode can be reported.
+//!
+//! ```rust.{example}
+//! extern crate conparse;
+//!
+//! use conparse::conparse::{ConfigParser,ParserOptions};
+//!
+//! fn main() {
+//!     let mut opts = ParserOptions::new();
+//!     opts.inline_comment_prefixes = vec![";".to_string(), "#".to_string()];
+//!     let mut cp = ConfigParser::with_options(&[], opts);
+//!     match cp.read_files(&["/etc/myapp/config.txt", "~/.myapprc"]) {
+//!         Ok(_) => {},
+//!         Err(e) => println!("Configuration problem: {}", e)
+//!     }
+//! }
+//! ```
+//!
+//! The available options are
+//!
+//! | Option | Meaning |
+//! |--------|:--------|
+//! | inline_comment_prefixes | Strip (and keep for writing) comments following an option value |
+//! | comments_in_continuations | Skip comment lines inside a continuation, rather than ending it |
+//! | indent_continuation | Treat lines indented deeper than their option as (newline separated) continuations, as Python does |
+//! | quoted_values | Decode double quoted values with backslash escapes, and quote values on writing where needed |
+//! | strict | Report sections or options repeated within one source as errors (other than `+=` lines, or any repeat under `multi_value`) |
+//! | global_options | Discard options before the first section (the default), or collect them into a named section or the default section |
+//! | default_section | The name of the section holding per-file defaults (`DEFAULT` unless changed), or `None` for no such section |
+//! | explicit_inheritance | `[child : parent]` headers make `child` inherit options from `parent` |
+//! | dotted_inheritance | `[a.b]` inherits options from `[a]` |
+//! | multi_value | Repeated keys and `key[]` lines accumulate values, see `get_all` |
+//! | append_operator | `key += value` extends the value an option has, inherits or defaults to, and with `multi_value` an empty `key =` clears it |
+//! | allow_includes | `include = path`, `!include path` and `include_dir` lines read other files in place |
+//! | max_include_depth | How deeply includes may nest (10 unless changed) |
+//! | sorted_output | Write sections and options sorted by name, rather than in the order they were read or added |
+//! | lossless | Keep the text read, comments and formatting included, so that it is written back unchanged apart from edits |
+//! | limits | Bounds on line lengths, section and option counts, values per option, interpolation depth and value size (none unless set) |
+//! | encoding | How files and readers are decoded: `Utf8Strict` (the default), `Utf8Lossy`, `Latin1` or `Windows1252` |
+//!
+//! Configuration from untrusted sources should be read with `limits`
+//! set, for instance to `Limits::untrusted()`, so that a hostile file
+//! cannot exhaust memory with huge lines or with interpolations like
+//! `a = %(b)s%(b)s`, `b = %(c)s%(c)s` which double at each step.
+//!
+//! Files saved on Windows read just like any others: `\r\n` line
+//! endings are taken as `\n`, and a UTF-8 byte order mark is
+//! ignored. Legacy files in other encodings can be read by setting
+//! `encoding`, which applies to files and readers but not to strings.
+//!
+//! Where a section inherits from another, options which it lacks
+//! are looked for in its parent, then its grandparent and so on,
+//! before the defaults are consulted. Inherited values are
+//! interpolated using the options of the section which was asked
+//! for, so a parent can hold templates like `%(host)s:%(port)s` for
+//! its children to fill in.
+//!
 //! ## Setting Configuration Values
 //!  
 //! It is possible to set new sections and options within a
@@ -211,6 +343,13 @@
 //! supply a file name, and the configuration data will be written to
 //! that file, with default permissions and ownership.
 //!
+//! Sections and options are written in the order they were read or
+//! added (or sorted, with the `sorted_output` option), but comments
+//! and layout are lost. To edit files people maintain by hand, use
+//! the `lossless` option: the text read is then written back byte
+//! for byte, and `set`, `remove_option`, `add_section` and
+//! `remove_section` change only the lines concerned.
+//!
 //! ## Convenience Getter Methods
 //! 
 //! Like the Python equivalent, some methods to parse values of
@@ -233,9 +372,30 @@
 //! Lastly, the `getfloat` method can be used to coerce the string
 //! into a `f64` type.
 //!
+//! ## Streaming Events
+//!
+//! `ConfigParser` is built on `Events`, an iterator over the pieces
+//! of configuration text as they are read: section headers, options,
+//! continuation lines, includes, comments, blank lines and invalid
+//! lines, each with its line number. Code which only wants part of a
+//! file, or wants to check or transform it, can consume these itself
+//! without building a parser; `raw` gives the exact text of each.
+//!
+//! ## Borrowed Parsing
+//!
+//! Where many small configurations are read only to look a few
+//! values up, `ConfigRef::from_str` avoids copying them: its section
+//! names, keys and values are slices of the text it was given. It
+//! reads text as `ConfigParser::from_str` does, has the same `get`,
+//! `get_raw` and typed getters, and `to_parser` turns it into a
+//! `ConfigParser` when the full interface is wanted. `from_str` has
+//! no limits, so text from untrusted sources should be read with
+//! `ConfigRef::with_limits` instead.
+//!
 
 #[macro_use] extern crate log;
 extern crate env_logger;
 
 pub mod conparse;
 pub mod expand;
+pub mod ordered;
@@ -0,0 +1,126 @@
+//! A map which remembers the order its keys were inserted in, so
+//! that sections and options can be listed (and written) in the
+//! order they were read or set
+
+use std::collections::HashMap;
+use std::slice;
+
+/// A map from `String` keys which iterates in the order the keys
+/// were first inserted. Replacing the value of a key leaves it where
+/// it was; removing a key and inserting it again moves it to the end.
+#[derive(Clone,Debug)]
+pub struct OrderedMap<V> {
+    keys : Vec<String>,
+    map : HashMap<String, V>
+}
+
+impl<V> OrderedMap<V> {
+    pub fn new() -> OrderedMap<V> {
+        OrderedMap{ keys : vec![], map : HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains_key(&self, k : &str) -> bool {
+        self.map.contains_key(k)
+    }
+
+    pub fn get(&self, k : &str) -> Option<&V> {
+        self.map.get(k)
+    }
+
+    pub fn get_mut(&mut self, k : &str) -> Option<&mut V> {
+        self.map.get_mut(k)
+    }
+
+    /// Sets the value of a key, returning the value it replaced (if
+    /// any). A new key goes at the end.
+    pub fn insert(&mut self, k : String, v : V) -> Option<V> {
+        match self.map.insert(k.clone(), v) {
+            None => {
+                self.keys.push(k);
+                None
+            },
+            old => old
+        }
+    }
+
+    /// The value of a key, first inserting one made by `f` at the
+    /// end if there is none
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, k : &str, f : F) -> &mut V {
+        if ! self.map.contains_key(k) {
+            self.keys.push(k.to_string());
+            self.map.insert(k.to_string(), f());
+        }
+        self.map.get_mut(k).unwrap()
+    }
+
+    pub fn remove(&mut self, k : &str) -> Option<V> {
+        match self.map.remove(k) {
+            Some(v) => {
+                self.keys.retain(|key| key.as_slice() != k);
+                Some(v)
+            },
+            None => None
+        }
+    }
+
+    /// The keys, in order
+    pub fn keys(&self) -> slice::Iter<String> {
+        self.keys.iter()
+    }
+
+    /// The keys and their values, in order
+    pub fn iter(&self) -> Iter<V> {
+        Iter{ keys : self.keys.iter(), map : &self.map }
+    }
+}
+
+/// An iterator over the keys and values of an `OrderedMap`, in order
+pub struct Iter<'a, V: 'a> {
+    keys : slice::Iter<'a, String>,
+    map : &'a HashMap<String, V>
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a String, &'a V);
+
+    fn next(&mut self) -> Option<(&'a String, &'a V)> {
+        let map = self.map;
+        match self.keys.next() {
+            Some(k) => map.get(k).map(|v| (k, v)),
+            None => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OrderedMap;
+
+    #[test]
+    fn test_order() {
+        let mut m : OrderedMap<usize> = OrderedMap::new();
+        for (i, k) in ["zulu", "alpha", "mike"].iter().enumerate() {
+            assert!(m.insert(k.to_string(), i).is_none());
+        }
+        assert_eq!(m.insert("alpha".to_string(), 10), Some(1));
+        *m.get_or_insert_with("bravo", || 3) += 1;
+        *m.get_or_insert_with("zulu", || 3) += 1;
+        let ks : Vec<&String> = m.keys().collect();
+        assert_eq!(ks, vec![&"zulu", &"alpha", &"mike", &"bravo"]);
+        assert_eq!(m.remove("alpha"), Some(10));
+        assert!(m.remove("alpha").is_none());
+        m.insert("alpha".to_string(), 5);
+        let kvs : Vec<(&String, &usize)> = m.iter().collect();
+        assert_eq!(kvs, vec![(&"zulu".to_string(), &1), (&"mike".to_string(), &2),
+                             (&"bravo".to_string(), &4), (&"alpha".to_string(), &5)]);
+        assert_eq!(m.len(), 4);
+    }
+}